@@ -21,6 +21,9 @@ import Outing from '../routes/outings/details';
 import OutingResults from '../routes/outings/results';
 
 const fetchOpts: IncomingOptions = {
+  // The API sends amounts as exact decimal strings by default, but we still
+  // do our math on plain numbers so ask for the float format instead
+  headers: { Accept: 'application/json; amounts=float' },
   interceptors: {
     response: async ({ response }) => {
       const res = response;
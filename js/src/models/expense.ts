@@ -10,25 +10,68 @@ import useFetch from 'use-http';
 import { type DateTime } from 'luxon';
 import { useCallback } from 'preact/hooks';
 
+export type ExpenseKind = 'purchase' | 'refund' | 'transfer';
+
 export interface Expense {
   expenseId: number;
   createdAt: DateTime;
+  incurredAt: string;
   outingId: string;
   personName: string;
   amount: number;
   description?: string;
+  category?: string;
+  tags: string[];
+  kind: ExpenseKind;
+  recipient: string | null;
+}
+
+export interface Transfer {
+  transferId: number;
+  createdAt: DateTime;
+  incurredAt: string;
+  outingId: string;
+  from: string;
+  to: string;
+  amount: number;
+  description?: string;
+}
+
+export interface BillItem {
+  personName: string;
+  amount: number;
+  description?: string;
+}
+
+export interface BillExtra {
+  amount: number;
+  description?: string;
+}
+
+export interface ExpenseShare {
+  personName: string;
+  subtotal: number;
+  extras: number;
+  share: number;
+}
+
+export interface ItemizedExpense extends Expense {
+  items: BillItem[];
+  extras: BillExtra[];
+  shares: ExpenseShare[];
 }
 
 export function useCreateExpense(personName: string, outingId: string) {
   const { post } = useFetch<Expense>('/expenses');
 
   return useCallback(
-    (amount: number, description?: string) =>
+    (amount: number, description?: string, category?: string) =>
       post({
         outing_id: outingId,
         person_name: personName,
         amount,
         description,
+        category,
       }),
     [personName, outingId, post]
   );
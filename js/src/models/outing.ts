@@ -17,6 +17,9 @@ export interface Outing {
   outingId: string;
   createdAt: DateTime;
   name: string;
+  rounding: RoundingPolicy;
+  groupId: string | null;
+  settledAt: string | null;
 }
 
 export interface OutingDetails extends Outing {
@@ -27,18 +30,30 @@ export interface Balance {
   total: number;
 }
 
+export type RoundingPolicy =
+  | 'cent'
+  | 'bankers'
+  | 'payers_up'
+  | 'nickel'
+  | 'whole';
+
 export interface OutingResult {
   from: string;
   to: string;
   amount: number;
 }
 
+export interface OutingResults {
+  rounding: RoundingPolicy;
+  results: OutingResult[];
+}
+
 export function useOutings() {
   return useFetch<Outing[]>('/outings', []);
 }
 
 export function useFinishOuting(id: string) {
-  return useBlankSafeFetch<OutingResult[]>(`/outings/${id}/finish`, [id]);
+  return useBlankSafeFetch<OutingResults>(`/outings/${id}/finish`, [id]);
 }
 
 export function useCreateOuting() {
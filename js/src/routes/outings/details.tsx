@@ -21,7 +21,11 @@ import {
 } from '../../components/common';
 import { GlobalContext } from '../../context';
 import { formatUsd } from '../../utils';
-import { useCreateExpense, type Expense } from '../../models/expense';
+import {
+  useCreateExpense,
+  type Expense,
+  type ExpenseKind,
+} from '../../models/expense';
 import {
   type Balance,
   type OutingDetails,
@@ -41,11 +45,17 @@ const CreateExpense = ({ refreshExpenses }: CreateExpenseProps) => {
 
   const [amount, setAmount] = useState(0);
   const [description, setDescription] = useState('');
+  const [category, setCategory] = useState('');
 
   async function createExpenseAndRefresh() {
     if (amount) {
       const trimmedDesc = description.replace(/\s+/g, ' ').trim();
-      await createExpense(amount, trimmedDesc || undefined);
+      const trimmedCategory = category.replace(/\s+/g, ' ').trim();
+      await createExpense(
+        amount,
+        trimmedDesc || undefined,
+        trimmedCategory || undefined
+      );
       refreshExpenses();
     }
   }
@@ -63,6 +73,12 @@ const CreateExpense = ({ refreshExpenses }: CreateExpenseProps) => {
     }
   }
 
+  function handleCategoryInput(e: Event) {
+    if (e.target instanceof HTMLInputElement) {
+      setCategory(e.target.value);
+    }
+  }
+
   return (
     <div class="mb-8">
       <Callout>Add a new expense:</Callout>
@@ -71,6 +87,10 @@ const CreateExpense = ({ refreshExpenses }: CreateExpenseProps) => {
           Description (optional):
         </Label>
         <Input onInput={handleDescInput} />
+        <Label class="leading-none flex flex-col justify-center content-center">
+          Category (optional):
+        </Label>
+        <Input onInput={handleCategoryInput} />
         <Label class="leading-none flex flex-col justify-center content-center">
           Amount:
         </Label>
@@ -83,6 +103,14 @@ const CreateExpense = ({ refreshExpenses }: CreateExpenseProps) => {
   );
 };
 
+// Refunds are stored with a positive amount, so they get a sign and a label
+// of their own to tell them apart from purchases
+const EXPENSE_VERBS: Record<ExpenseKind, string> = {
+  purchase: ' by ',
+  refund: ' refunded to ',
+  transfer: ' from ',
+};
+
 interface OutingPageProps {
   outing: OutingDetails;
   balance?: Balance;
@@ -103,11 +131,27 @@ const OutingPage = ({
         <CreateExpense {...{ refreshExpenses }} />
         <ul class="pt-4 max-w-prose">
           {expenses?.map(
-            ({ expenseId, amount, createdAt, personName, description }) => (
+            ({
+              expenseId,
+              amount,
+              createdAt,
+              personName,
+              description,
+              kind,
+              recipient,
+            }) => (
               <li key={expenseId} class="mb-1">
-                <span class="font-semibold">{formatUsd(amount)}</span>
-                <span> by </span>
+                <span class="font-semibold">
+                  {formatUsd(kind === 'refund' ? -amount : amount)}
+                </span>
+                <span>{EXPENSE_VERBS[kind]}</span>
                 <span class="font-semibold">{personName}</span>
+                {kind === 'transfer' && recipient && (
+                  <>
+                    <span> to </span>
+                    <span class="font-semibold">{recipient}</span>
+                  </>
+                )}
                 <span> @ </span>
                 <span class="italic">
                   {createdAt.toLocaleString(DateTime.DATETIME_FULL)}
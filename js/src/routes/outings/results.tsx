@@ -97,7 +97,8 @@ export const ResultsRoute: FunctionalComponent = () => {
 
   const { data: outing, error: outingError } = useOuting(outingId);
   const { data: balance, error: balanceError } = useOutingBalance(outingId);
-  const { data: results, error: resultsError } = useFinishOuting(outingId);
+  const { data: finished, error: resultsError } = useFinishOuting(outingId);
+  const results = finished?.results;
 
   const error = outingError ?? balanceError ?? resultsError;
 
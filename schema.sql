@@ -4,6 +4,25 @@ CREATE TABLE IF NOT EXISTS outings (
   name TEXT NOT NULL
 );
 
+ALTER TABLE outings ADD COLUMN IF NOT EXISTS rounding TEXT NOT NULL DEFAULT 'cent';
+
+-- Outings with a policy we don't know about can't be read back, so those go
+-- back to the default
+UPDATE outings SET rounding = 'cent'
+WHERE rounding NOT IN ('cent', 'bankers', 'payers_up', 'nickel', 'whole');
+
+DO $$
+BEGIN
+  IF NOT EXISTS (
+    SELECT FROM pg_constraint
+    WHERE conrelid = 'outings'::regclass AND conname = 'outings_rounding_check'
+  ) THEN
+    ALTER TABLE outings
+      ADD CONSTRAINT outings_rounding_check
+        CHECK (rounding IN ('cent', 'bankers', 'payers_up', 'nickel', 'whole'));
+  END IF;
+END $$;
+
 CREATE TABLE IF NOT EXISTS outing_people (
   outing_id INTEGER REFERENCES outings(outing_id),
   name TEXT,
@@ -19,3 +38,145 @@ CREATE TABLE IF NOT EXISTS expenses (
   description TEXT,
   FOREIGN KEY (outing_id, person_name) REFERENCES outing_people(outing_id, name)
 );
+
+CREATE TABLE IF NOT EXISTS outing_constraints (
+  outing_id INTEGER NOT NULL,
+  payer TEXT NOT NULL,
+  payee TEXT NOT NULL,
+  kind TEXT NOT NULL CHECK (kind IN ('forbidden', 'preferred')),
+  PRIMARY KEY (outing_id, payer, payee),
+  FOREIGN KEY (outing_id, payer) REFERENCES outing_people(outing_id, name),
+  FOREIGN KEY (outing_id, payee) REFERENCES outing_people(outing_id, name),
+  CHECK (payer <> payee)
+);
+
+CREATE TABLE IF NOT EXISTS groups (
+  group_id SERIAL PRIMARY KEY,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  name TEXT NOT NULL
+);
+
+ALTER TABLE outings ADD COLUMN IF NOT EXISTS group_id INTEGER REFERENCES groups(group_id);
+ALTER TABLE outings ADD COLUMN IF NOT EXISTS settled_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS group_members (
+  group_id INTEGER REFERENCES groups(group_id),
+  name TEXT,
+  PRIMARY KEY (group_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS recurring_expenses (
+  recurring_id SERIAL PRIMARY KEY,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  group_id INTEGER NOT NULL,
+  person_name TEXT NOT NULL,
+  amount NUMERIC(9,4) NOT NULL,
+  description TEXT,
+  schedule TEXT NOT NULL CHECK (schedule IN ('weekly', 'monthly')),
+  starts_on DATE NOT NULL DEFAULT CURRENT_DATE,
+  FOREIGN KEY (group_id, person_name) REFERENCES group_members(group_id, name)
+);
+
+-- Each time a recurring expense comes due, it gets its own outing. The primary
+-- key is what stops the same occurrence from being added twice.
+CREATE TABLE IF NOT EXISTS recurring_occurrences (
+  recurring_id INTEGER REFERENCES recurring_expenses(recurring_id) ON DELETE CASCADE,
+  occurs_on DATE,
+  outing_id INTEGER REFERENCES outings(outing_id),
+  PRIMARY KEY (recurring_id, occurs_on)
+);
+
+ALTER TABLE expenses ADD COLUMN IF NOT EXISTS category TEXT;
+ALTER TABLE expenses ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+
+-- When the money was actually spent, which defaults to the day the expense was
+-- added
+ALTER TABLE expenses ADD COLUMN IF NOT EXISTS incurred_at DATE;
+UPDATE expenses SET incurred_at = (created_at AT TIME ZONE 'UTC')::date WHERE incurred_at IS NULL;
+
+CREATE OR REPLACE FUNCTION default_incurred_at() RETURNS trigger AS $$
+BEGIN
+  IF NEW.incurred_at IS NULL THEN
+    NEW.incurred_at := (NEW.created_at AT TIME ZONE 'UTC')::date;
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS expenses_incurred_at ON expenses;
+CREATE TRIGGER expenses_incurred_at BEFORE INSERT ON expenses
+  FOR EACH ROW EXECUTE FUNCTION default_incurred_at();
+
+ALTER TABLE expenses ALTER COLUMN incurred_at SET NOT NULL;
+
+-- At most one receipt per expense. The image itself lives in the receipt store
+-- (S3 or the local filesystem) under `object_key`.
+CREATE TABLE IF NOT EXISTS receipts (
+  expense_id INTEGER PRIMARY KEY REFERENCES expenses(expense_id) ON DELETE CASCADE,
+  uploaded_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  object_key TEXT NOT NULL,
+  content_type TEXT NOT NULL,
+  size INTEGER NOT NULL
+);
+
+-- Purchases are split between everyone in the outing, refunds are credited back
+-- to everyone, and transfers are only between the payer and the recipient.
+ALTER TABLE expenses ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'purchase'
+  CHECK (kind IN ('purchase', 'refund', 'transfer'));
+ALTER TABLE expenses ADD COLUMN IF NOT EXISTS recipient TEXT;
+
+-- Refunds used to be entered as negative purchases
+UPDATE expenses SET kind = 'refund', amount = -amount WHERE kind = 'purchase' AND amount < 0;
+
+DO $$
+BEGIN
+  IF NOT EXISTS (
+    SELECT FROM pg_constraint
+    WHERE conrelid = 'expenses'::regclass AND conname = 'expenses_recipient_fkey'
+  ) THEN
+    ALTER TABLE expenses
+      ADD CONSTRAINT expenses_recipient_fkey
+        FOREIGN KEY (outing_id, recipient) REFERENCES outing_people(outing_id, name),
+      ADD CONSTRAINT expenses_recipient_check
+        CHECK ((kind = 'transfer') = (recipient IS NOT NULL) AND recipient IS DISTINCT FROM person_name),
+      ADD CONSTRAINT expenses_amount_check CHECK (amount >= 0);
+  END IF;
+END $$;
+
+-- Recurring expenses are added as purchases, so they can't be negative either.
+-- Any that already are get skipped when they come due.
+DO $$
+BEGIN
+  IF NOT EXISTS (
+    SELECT FROM pg_constraint
+    WHERE conrelid = 'recurring_expenses'::regclass AND conname = 'recurring_expenses_amount_check'
+  ) THEN
+    ALTER TABLE recurring_expenses
+      ADD CONSTRAINT recurring_expenses_amount_check CHECK (amount >= 0) NOT VALID;
+  END IF;
+END $$;
+
+-- The lines of an itemized bill: what each person ordered, plus extras like tax,
+-- tip and fees, which have no person_name
+CREATE TABLE IF NOT EXISTS expense_lines (
+  expense_id INTEGER REFERENCES expenses(expense_id) ON DELETE CASCADE,
+  line_no INTEGER,
+  person_name TEXT,
+  description TEXT,
+  amount NUMERIC(9,4) NOT NULL CHECK (amount >= 0),
+  PRIMARY KEY (expense_id, line_no)
+);
+
+-- What each person owes for an itemized bill: what they ordered, plus the
+-- extras in proportion to that. Derived from expense_lines when the bill is
+-- added. Settlement splits expenses with shares by these instead of evenly.
+CREATE TABLE IF NOT EXISTS expense_shares (
+  expense_id INTEGER REFERENCES expenses(expense_id) ON DELETE CASCADE,
+  outing_id INTEGER NOT NULL,
+  person_name TEXT NOT NULL,
+  subtotal NUMERIC(9,4) NOT NULL,
+  extras NUMERIC(9,4) NOT NULL,
+  share NUMERIC(9,4) NOT NULL CHECK (share = subtotal + extras),
+  PRIMARY KEY (expense_id, person_name),
+  FOREIGN KEY (outing_id, person_name) REFERENCES outing_people(outing_id, name)
+);
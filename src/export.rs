@@ -0,0 +1,411 @@
+/*
+ * Copyright © 2023 Jonathan Ming
+ *
+ * This file is part of Birdie.
+ *
+ * For information about warranty and licensing, see the disclaimer in
+ * src/lib.rs as well as the LICENSE file.
+ */
+use csv::Writer;
+use rust_decimal::RoundingStrategy;
+use sqlx::types::Decimal;
+
+use crate::models::{Expense, ExpenseKind, ExpenseShare, LedgerParams, OutingResult};
+use crate::settle::allocate_units;
+
+// Column order here is part of the export format, so only ever append to these
+pub const EXPENSE_COLUMNS: [&str; 11] = [
+    "expense_id",
+    "created_at",
+    "outing_id",
+    "person_name",
+    "amount",
+    "description",
+    "category",
+    "tags",
+    "incurred_at",
+    "kind",
+    "recipient",
+];
+
+pub const RESULT_COLUMNS: [&str; 3] = ["from", "to", "amount"];
+
+fn finish(writer: Writer<Vec<u8>>) -> Result<Vec<u8>, csv::Error> {
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Writes expenses as CSV. Amounts are written from their `Decimal` string
+/// representation so that they never pass through a float.
+pub fn expenses_csv(expenses: &[Expense]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = Writer::from_writer(vec![]);
+    writer.write_record(EXPENSE_COLUMNS)?;
+    for expense in expenses {
+        writer.write_record([
+            expense.expense_id.to_string(),
+            expense.created_at.to_rfc3339(),
+            String::from(expense.outing_id.clone()),
+            expense.person_name.clone(),
+            expense.amount.to_string(),
+            expense.description.clone().unwrap_or_default(),
+            expense.category.clone().unwrap_or_default(),
+            expense.tags.join(";"),
+            expense.incurred_at.to_string(),
+            expense.kind.to_string(),
+            expense.recipient.clone().unwrap_or_default(),
+        ])?;
+    }
+    finish(writer)
+}
+
+pub fn results_csv(results: &[OutingResult]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = Writer::from_writer(vec![]);
+    writer.write_record(RESULT_COLUMNS)?;
+    for result in results {
+        writer.write_record([&result.from, &result.to, &result.amount.to_string()])?;
+    }
+    finish(writer)
+}
+
+/// Rounds an amount to whole cents, returned as an integer number of cents.
+fn to_cents(amount: Decimal) -> i128 {
+    let mut rounded = amount.round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero);
+    rounded.rescale(2);
+    rounded.mantissa()
+}
+
+/// Splits a number of minor units (cents, say) into `n` shares which add back
+/// up to exactly that many units. Whatever doesn't divide evenly goes a unit at
+/// a time to the first shares.
+fn split_units(units: i128, n: usize) -> Vec<i128> {
+    let base = units / n as i128;
+    let remainder = units - base * n as i128;
+    (0..n)
+        .map(|i| {
+            if (i as i128) < remainder.abs() {
+                base + remainder.signum()
+            } else {
+                base
+            }
+        })
+        .collect()
+}
+
+/// A Splitwise payment row, where `from` gives `to` some money and nobody
+/// else is involved
+fn splitwise_payment(
+    people: &[String],
+    date: String,
+    description: String,
+    from: &str,
+    to: &str,
+    amount: Decimal,
+    currency: &str,
+) -> Vec<String> {
+    let amount = to_cents(amount);
+    let position = |name: &str| people.iter().position(|p| p == name);
+    let mut balances = vec![0; people.len()];
+    if let (Some(from), Some(to)) = (position(from), position(to)) {
+        balances[from] += amount;
+        balances[to] -= amount;
+    }
+
+    let mut record = vec![
+        date,
+        description,
+        "Payment".to_string(),
+        Decimal::new(amount as i64, 2).to_string(),
+        currency.to_string(),
+    ];
+    record.extend(
+        balances
+            .iter()
+            .map(|b| Decimal::new(*b as i64, 2).to_string()),
+    );
+    record
+}
+
+/// Writes an outing in the CSV layout Splitwise uses for its exports and
+/// spreadsheet imports: one row per expense with its cost, followed by one
+/// column per person holding how much that row changes their balance.
+/// Splitwise only deals in cents, so everything gets rounded to cents, with
+/// each row's shares adjusted so that it still nets to zero. Itemized bills are
+/// split by their shares rather than evenly. Refunds have a negative cost, and
+/// transfers are written as payments.
+///
+/// Settlements are written as Splitwise payments. Since Splitwise treats those
+/// as already paid, callers should only pass them in once they really have
+/// been.
+pub fn splitwise_csv(
+    people: &[String],
+    expenses: &[Expense],
+    shares: &[ExpenseShare],
+    settlements: &[OutingResult],
+    currency: &str,
+) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = Writer::from_writer(vec![]);
+
+    let mut header = vec!["Date", "Description", "Category", "Cost", "Currency"];
+    header.extend(people.iter().map(String::as_str));
+    writer.write_record(&header)?;
+
+    let position = |name: &str| people.iter().position(|p| p == name);
+
+    for expense in expenses {
+        if let (ExpenseKind::Transfer, Some(recipient)) = (expense.kind, &expense.recipient) {
+            writer.write_record(splitwise_payment(
+                people,
+                expense.incurred_at.to_string(),
+                expense
+                    .description
+                    .clone()
+                    .unwrap_or_else(|| format!("{} paid {}", expense.person_name, recipient)),
+                &expense.person_name,
+                recipient,
+                expense.amount,
+                currency,
+            ))?;
+            continue;
+        }
+
+        let cost = to_cents(expense.cost());
+        let mut balances: Vec<i128> = match bill_shares(expense, shares, people) {
+            // Rounding every share to cents could leave the row off by a cent
+            // or two, so divide up the rounded cost in the same proportions
+            Some(weights) => {
+                let cents: Vec<i128> = weights.iter().copied().map(to_cents).collect();
+                if cents.iter().sum::<i128>() != 0 {
+                    allocate_units(cost, &cents)
+                } else {
+                    // Every share rounds to nothing, so go by the exact shares
+                    let exact: Vec<i128> = weights
+                        .into_iter()
+                        .map(|mut w| {
+                            w.rescale(4);
+                            w.mantissa()
+                        })
+                        .collect();
+                    allocate_units(cost, &exact)
+                }
+            }
+            None => split_units(cost, people.len()),
+        }
+        .into_iter()
+        .map(|share| -share)
+        .collect();
+        if let Some(payer) = position(&expense.person_name) {
+            balances[payer] += cost;
+        }
+
+        let mut record = vec![
+            expense.incurred_at.to_string(),
+            expense.description.clone().unwrap_or_default(),
+            expense.category.clone().unwrap_or_else(|| "General".into()),
+            Decimal::new(cost as i64, 2).to_string(),
+            currency.to_string(),
+        ];
+        record.extend(
+            balances
+                .iter()
+                .map(|b| Decimal::new(*b as i64, 2).to_string()),
+        );
+        writer.write_record(&record)?;
+    }
+
+    let today = chrono::Utc::now().date_naive().to_string();
+    for settlement in settlements {
+        writer.write_record(splitwise_payment(
+            people,
+            today.clone(),
+            format!("{} paid {}", settlement.from, settlement.to),
+            &settlement.from,
+            &settlement.to,
+            settlement.amount,
+            currency,
+        ))?;
+    }
+
+    finish(writer)
+}
+
+/// Everybody's share of an itemized bill, in the same order as `people`, or
+/// `None` if the expense isn't itemized and so is split evenly.
+fn bill_shares(
+    expense: &Expense,
+    shares: &[ExpenseShare],
+    people: &[String],
+) -> Option<Vec<Decimal>> {
+    let shares: Vec<&ExpenseShare> = shares
+        .iter()
+        .filter(|s| s.expense_id == expense.expense_id)
+        .collect();
+    if shares.is_empty() {
+        return None;
+    }
+
+    Some(
+        people
+            .iter()
+            .map(|person| {
+                shares
+                    .iter()
+                    .find(|s| &s.person_name == person)
+                    .map_or(Decimal::ZERO, |s| s.share)
+            })
+            .collect(),
+    )
+}
+
+/// Splits an amount evenly into `n` shares at `scale` decimal places, which add
+/// back up to exactly the original amount as long as it didn't have more
+/// decimal places than that to begin with.
+fn split_decimal(amount: Decimal, n: usize, scale: u32) -> Vec<Decimal> {
+    let mut amount = amount;
+    amount.rescale(scale);
+    split_units(amount.mantissa(), n)
+        .into_iter()
+        .map(|units| Decimal::from_i128_with_scale(units, scale))
+        .collect()
+}
+
+fn account_name(template: &str, outing_name: &str, person: &str) -> String {
+    template
+        .replace("{outing}", outing_name)
+        .replace("{person}", person)
+}
+
+fn write_transaction(
+    journal: &mut String,
+    date: impl std::fmt::Display,
+    payee: &str,
+    postings: &[(String, Decimal)],
+    currency: &str,
+) {
+    let width = postings.iter().map(|(a, _)| a.len()).max().unwrap_or(0);
+    journal.push_str(&format!("{} * {}\n", date, payee));
+    for (account, amount) in postings {
+        journal.push_str(&format!(
+            "    {:<width$}  {} {}\n",
+            account,
+            amount,
+            currency,
+            width = width
+        ));
+    }
+    journal.push('\n');
+}
+
+/// Writes an outing as a ledger/hledger journal. Each expense credits its
+/// payer's asset account and debits every person's receivable account with
+/// their share (the other way around for refunds), and each transfer or
+/// settlement moves money between two asset accounts.
+/// That way a person's asset and receivable accounts sum to their exact
+/// position in the outing. Settlements are rounded per the outing's rounding
+/// policy, so once they've been paid the accounts are only as close to zero as
+/// that rounding.
+///
+/// Shares are exact: they are split at the expense's own precision (but at
+/// least cents), with any leftover units going to the first people in `people`
+/// order. Itemized bills use their own shares, which are already exact.
+pub fn ledger_journal(
+    outing_name: &str,
+    people: &[String],
+    expenses: &[Expense],
+    shares: &[ExpenseShare],
+    settlements: &[OutingResult],
+    params: &LedgerParams,
+) -> String {
+    let asset = |person: &str| account_name(&params.asset_account, outing_name, person);
+    let receivable = |person: &str| account_name(&params.receivable_account, outing_name, person);
+
+    let mut journal = format!("; Birdie outing: {}\n\n", outing_name);
+
+    for expense in expenses {
+        if let (ExpenseKind::Transfer, Some(recipient)) = (expense.kind, &expense.recipient) {
+            let postings = [
+                (asset(recipient), expense.amount),
+                (asset(&expense.person_name), -expense.amount),
+            ];
+            let payee = match &expense.description {
+                Some(description) => {
+                    format!(
+                        "{} ({} pays {})",
+                        description, expense.person_name, recipient
+                    )
+                }
+                None => format!("{} pays {}", expense.person_name, recipient),
+            };
+            write_transaction(
+                &mut journal,
+                expense.incurred_at,
+                &payee,
+                &postings,
+                &params.currency,
+            );
+            continue;
+        }
+
+        let bill = bill_shares(expense, shares, people);
+        let scale = bill
+            .iter()
+            .flatten()
+            .chain([&expense.amount])
+            .map(|a| a.normalize().scale())
+            .max()
+            .unwrap_or_default()
+            .max(2);
+        let mut amount = expense.cost();
+        amount.rescale(scale);
+
+        let mut postings = vec![(asset(&expense.person_name), -amount)];
+        match bill {
+            Some(bill) => postings.extend(
+                people
+                    .iter()
+                    .zip(bill)
+                    .filter(|(_, share)| !share.is_zero())
+                    .map(|(person, mut share)| {
+                        share.rescale(scale);
+                        (receivable(person), share)
+                    }),
+            ),
+            None => postings.extend(
+                people
+                    .iter()
+                    .zip(split_decimal(amount, people.len(), scale))
+                    .map(|(person, share)| (receivable(person), share)),
+            ),
+        }
+
+        let payee = match &expense.description {
+            Some(description) => format!("{} ({})", description, expense.person_name),
+            None if expense.kind == ExpenseKind::Refund => {
+                format!("Refunded to {}", expense.person_name)
+            }
+            None => format!("Paid by {}", expense.person_name),
+        };
+        write_transaction(
+            &mut journal,
+            expense.incurred_at,
+            &payee,
+            &postings,
+            &params.currency,
+        );
+    }
+
+    let today = chrono::Utc::now().date_naive();
+    for settlement in settlements {
+        let postings = [
+            (asset(&settlement.to), settlement.amount),
+            (asset(&settlement.from), -settlement.amount),
+        ];
+        write_transaction(
+            &mut journal,
+            today,
+            &format!("{} pays {}", settlement.from, settlement.to),
+            &postings,
+            &params.currency,
+        );
+    }
+
+    journal
+}
@@ -0,0 +1,132 @@
+/*
+ * Copyright © 2023 Jonathan Ming
+ *
+ * This file is part of Birdie.
+ *
+ * For information about warranty and licensing, see the disclaimer in
+ * src/lib.rs as well as the LICENSE file.
+ */
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::types::Decimal;
+
+use crate::models::{
+    check_amount, check_kind, normalize_category, normalize_tags, ExpenseImportRow, ExpenseKind,
+    ImportRowError,
+};
+
+// The csv crate infers field types when deserializing into a Decimal, which
+// would send amounts through a float, so read them as strings and parse them
+// ourselves.
+#[derive(Deserialize)]
+struct CsvRow {
+    person_name: String,
+    amount: String,
+    description: Option<String>,
+    #[serde(default)]
+    category: Option<String>,
+    // Separated by semicolons, since CSV has no lists
+    #[serde(default)]
+    tags: Option<String>,
+    #[serde(default)]
+    incurred_at: Option<NaiveDate>,
+    #[serde(default)]
+    kind: Option<ExpenseKind>,
+    #[serde(default)]
+    recipient: Option<String>,
+}
+
+impl TryFrom<CsvRow> for ExpenseImportRow {
+    type Error = String;
+    fn try_from(row: CsvRow) -> Result<Self, String> {
+        let amount = Decimal::from_str(&row.amount)
+            .map_err(|_| format!("Invalid amount \"{}\"", row.amount))?;
+        Ok(Self {
+            person_name: row.person_name,
+            amount,
+            description: row.description,
+            category: row.category,
+            tags: row
+                .tags
+                .map(|t| t.split(';').map(str::to_string).collect())
+                .unwrap_or_default(),
+            incurred_at: row.incurred_at,
+            kind: row.kind.unwrap_or_default(),
+            recipient: row.recipient,
+        })
+    }
+}
+
+/// Parses each CSV record into a row, keeping per-row errors rather than
+/// bailing out on the first bad one. The header row is required and decides
+/// column order.
+pub fn parse_csv(body: &[u8]) -> Vec<Result<ExpenseImportRow, String>> {
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(body)
+        .deserialize::<CsvRow>()
+        .map(|record| record.map_err(|e| e.to_string())?.try_into())
+        .collect()
+}
+
+/// Parses a JSON array of rows, again keeping per-row errors.
+pub fn parse_json(body: &[u8]) -> Result<Vec<Result<ExpenseImportRow, String>>, String> {
+    let values: Vec<Value> = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    Ok(values
+        .into_iter()
+        .map(|v| serde_json::from_value(v).map_err(|e| e.to_string()))
+        .collect())
+}
+
+/// Validates every parsed row, returning the good rows only if there were no
+/// errors at all. Row numbers are 1-based and don't count a CSV header.
+pub fn validate(
+    parsed: Vec<Result<ExpenseImportRow, String>>,
+) -> Result<Vec<ExpenseImportRow>, Vec<ImportRowError>> {
+    let mut rows = Vec::with_capacity(parsed.len());
+    let mut errors = vec![];
+
+    for (i, row) in parsed.into_iter().enumerate() {
+        let checked = row.and_then(|mut row| {
+            row.person_name = row.person_name.trim().to_string();
+            if row.person_name.is_empty() {
+                return Err("person_name must not be blank".to_string());
+            }
+            check_amount(&row.amount).map_err(str::to_string)?;
+            row.recipient = row
+                .recipient
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty());
+            (row.kind, row.amount) = check_kind(
+                row.kind,
+                row.amount,
+                &row.person_name,
+                row.recipient.as_deref(),
+            )
+            .map_err(str::to_string)?;
+            // Blank descriptions are as good as none
+            row.description = row.description.filter(|d| !d.trim().is_empty());
+            row.category = normalize_category(row.category);
+            row.tags = normalize_tags(row.tags);
+            Ok(row)
+        });
+
+        match checked {
+            Ok(row) => rows.push(row),
+            Err(message) => errors.push(ImportRowError {
+                row: i + 1,
+                message,
+            }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(rows)
+    } else {
+        Err(errors)
+    }
+}
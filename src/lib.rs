@@ -22,29 +22,42 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::collections::VecDeque;
-
 use async_compression::tokio::bufread::GzipDecoder;
 use axum::{
-    extract::Path,
-    http::StatusCode,
-    routing::{get, get_service, post, put, Router},
+    body::Bytes,
+    extract::{ws::WebSocketUpgrade, DefaultBodyLimit, Multipart, Path, Query, Request},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, get_service, post, put, Router},
     Extension, Json,
 };
+use serde::Deserialize;
 use shuttle_runtime::CustomError;
 use shuttle_secrets::SecretStore;
-use sqlx::{types::Decimal, Executor, PgPool};
+use sqlx::{Executor, PgConnection, PgExecutor, PgPool};
 use tokio_tar::Archive;
 use tower_http::{
     services::{ServeDir, ServeFile},
     trace::TraceLayer,
 };
-use tracing::{error, info};
+use tracing::info;
+
+mod export;
+mod import;
+pub mod recurring;
+
+pub mod live;
+use live::{EventId, Hub, LiveMessage};
 
 pub mod models;
 use models::*;
 
+pub mod receipts;
+use receipts::{ReceiptStore, MAX_RECEIPT_SIZE};
+
 mod s3;
+mod settle;
 
 /// Utility function for mapping any error into a `400 Bad Request`
 /// response.
@@ -70,16 +83,24 @@ async fn create_outing(
 ) -> Result<Json<Outing>, (StatusCode, String)> {
     let result = sqlx::query_as(
         "WITH new_outing AS ( \
-           INSERT INTO outings(name) VALUES ($1) RETURNING * \
+           INSERT INTO outings(name, rounding, group_id) VALUES ($1, $3, $4) RETURNING * \
          ), \
          new_outing_person AS ( \
            INSERT INTO outing_people(outing_id, name) \
            SELECT outing_id, $2 FROM new_outing \
+         ), \
+         group_people AS ( \
+           INSERT INTO outing_people(outing_id, name) \
+           SELECT new_outing.outing_id, gm.name FROM new_outing \
+           JOIN group_members AS gm ON gm.group_id = new_outing.group_id \
+           WHERE gm.name <> $2 \
          ) \
          SELECT * FROM new_outing",
     )
     .bind(&payload.name)
     .bind(&payload.person_name)
+    .bind(payload.rounding)
+    .bind(&payload.group_id)
     .fetch_one(&pool)
     .await
     .map_err(bad_request)?;
@@ -87,6 +108,27 @@ async fn create_outing(
     Ok(Json(result))
 }
 
+async fn check_outing_exists(
+    pool: &PgPool,
+    outing_id: &OutingId,
+) -> Result<(), (StatusCode, String)> {
+    let exists: Option<(i32,)> =
+        sqlx::query_as("SELECT outing_id FROM outings WHERE outing_id = $1")
+            .bind(outing_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(internal_error)?;
+
+    if exists.is_some() {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            "Outing with given ID not found".to_string(),
+        ))
+    }
+}
+
 async fn retrieve_outing(
     Extension(pool): Extension<PgPool>,
     Path(outing_id): Path<OutingId>,
@@ -113,13 +155,126 @@ async fn retrieve_outing(
     }
 }
 
+async fn update_outing_rounding(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Json(payload): Json<RoundingUpdate>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query("UPDATE outings SET rounding = $2 WHERE outing_id = $1")
+        .bind(&outing_id)
+        .bind(payload.rounding)
+        .execute(&pool)
+        .await
+        .map_err(bad_request)?;
+
+    if result.rows_affected() == 0 {
+        Err((
+            StatusCode::NOT_FOUND,
+            "Outing with given ID not found".to_string(),
+        ))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+async fn query_outing_constraints(
+    pool: &PgPool,
+    outing_id: &OutingId,
+) -> Result<Vec<SettlementConstraint>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT payer, payee, kind FROM outing_constraints \
+         WHERE outing_id = $1 ORDER BY payer, payee",
+    )
+    .bind(outing_id)
+    .fetch_all(pool)
+    .await
+}
+
+async fn retrieve_outing_constraints(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+) -> Result<Json<Vec<SettlementConstraint>>, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    let result = query_outing_constraints(&pool, &outing_id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(result))
+}
+
+/// Replaces all of an outing's constraints at once.
+async fn update_outing_constraints(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Json(payload): Json<Vec<SettlementConstraint>>,
+) -> Result<Json<Vec<SettlementConstraint>>, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    sqlx::query("DELETE FROM outing_constraints WHERE outing_id = $1")
+        .bind(&outing_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+
+    for constraint in &payload {
+        // The foreign keys and checks on the table catch people who aren't
+        // part of the outing, people paying themselves, and duplicate pairs
+        sqlx::query(
+            "INSERT INTO outing_constraints(outing_id, payer, payee, kind) \
+             VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&outing_id)
+        .bind(&constraint.payer)
+        .bind(&constraint.payee)
+        .bind(constraint.kind)
+        .execute(&mut *tx)
+        .await
+        .map_err(bad_request)?;
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+
+    let result = query_outing_constraints(&pool, &outing_id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(result))
+}
+
+async fn update_outing_group(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Json(payload): Json<OutingGroupUpdate>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query("UPDATE outings SET group_id = $2 WHERE outing_id = $1")
+        .bind(&outing_id)
+        .bind(&payload.group_id)
+        .execute(&pool)
+        .await
+        .map_err(bad_request)?;
+
+    if result.rows_affected() == 0 {
+        Err((
+            StatusCode::NOT_FOUND,
+            "Outing with given ID not found".to_string(),
+        ))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+/// How much the outing as a whole spent. Refunds count against what was spent,
+/// and transfers don't count at all since they stay between two people.
 async fn retrieve_outing_balance(
     Extension(pool): Extension<PgPool>,
     Path(outing_id): Path<OutingId>,
 ) -> Result<Json<Balance>, (StatusCode, String)> {
     let result = sqlx::query_as(
-        "SELECT COALESCE(SUM(amount), 0) AS total \
-         FROM expenses WHERE outing_id = $1",
+        "SELECT COALESCE(SUM(CASE kind WHEN 'refund' THEN -amount ELSE amount END), 0) AS total \
+         FROM expenses WHERE outing_id = $1 AND kind <> 'transfer'",
     )
     .bind(outing_id)
     .fetch_one(&pool)
@@ -129,27 +284,292 @@ async fn retrieve_outing_balance(
     Ok(Json(result))
 }
 
+async fn retrieve_outing_people(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+) -> Result<Json<Vec<PersonBalance>>, (StatusCode, String)> {
+    let rounding: Option<(RoundingPolicy,)> =
+        sqlx::query_as("SELECT rounding FROM outings WHERE outing_id = $1")
+            .bind(&outing_id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(internal_error)?;
+
+    let Some((rounding,)) = rounding else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Outing with given ID not found".to_string(),
+        ));
+    };
+
+    let people = query_person_expenses(&pool, outing_id, &SettlementPreview::default())
+        .await
+        .map_err(internal_error)?;
+    let diffs = settle::positions(&paid_from(&people), rounding);
+
+    Ok(Json(settle::balances(people, &diffs)))
+}
+
+async fn retrieve_outing_summary(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+) -> Result<Json<OutingSummary>, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    let categories: Vec<CategoryTotal> = sqlx::query_as(
+        "SELECT category, \
+           SUM(CASE kind WHEN 'refund' THEN -amount ELSE amount END) AS total, \
+           COUNT(*) AS expense_count \
+         FROM expenses WHERE outing_id = $1 AND kind <> 'transfer' \
+         GROUP BY category \
+         ORDER BY total DESC, category",
+    )
+    .bind(&outing_id)
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    let people = sqlx::query_as(
+        "SELECT person_name, category, \
+           SUM(CASE kind WHEN 'refund' THEN -amount ELSE amount END) AS total, \
+           COUNT(*) AS expense_count \
+         FROM expenses WHERE outing_id = $1 AND kind <> 'transfer' \
+         GROUP BY person_name, category \
+         ORDER BY person_name, total DESC, category",
+    )
+    .bind(&outing_id)
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    Ok(Json(OutingSummary {
+        total: categories.iter().map(|c| c.total).sum(),
+        categories,
+        people,
+    }))
+}
+
+/// Full-text search over an outing's expenses' descriptions and tags. The
+/// query can use web search syntax, like `gas -diesel` or `"gas station"`.
+async fn search_outing_expenses(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<Vec<ExpenseMatch>>, (StatusCode, String)> {
+    if params.q.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Search query must not be blank".to_string(),
+        ));
+    }
+
+    check_outing_exists(&pool, &outing_id).await?;
+
+    // Searches are only ever within one outing, which is few enough expenses
+    // that there's no need to index the search vectors. ts_headline doesn't
+    // escape anything, so matches get marked with characters that are taken
+    // out of the description beforehand, and swapped for tags once the rest
+    // has been escaped.
+    let mut result: Vec<ExpenseMatch> = sqlx::query_as(
+        "WITH q AS (SELECT websearch_to_tsquery('english', $2) AS query) \
+         SELECT ex.*, \
+           CASE WHEN ex.description IS NOT NULL THEN \
+             ts_headline('english', translate(ex.description, $3 || $4, ''), q.query, \
+               format('StartSel=%s, StopSel=%s', $3, $4)) \
+           END AS headline, \
+           ARRAY( \
+             SELECT tag FROM unnest(ex.tags) AS tag \
+             WHERE to_tsvector('english', tag) @@ q.query \
+           ) AS matched_tags \
+         FROM expenses AS ex, q, \
+           LATERAL ( \
+             SELECT setweight(to_tsvector('english', COALESCE(ex.description, '')), 'A') \
+               || setweight(to_tsvector('english', array_to_string(ex.tags, ' ')), 'B') AS document \
+           ) AS doc \
+         WHERE ex.outing_id = $1 AND doc.document @@ q.query \
+         ORDER BY ts_rank(doc.document, q.query) DESC, ex.expense_id",
+    )
+    .bind(&outing_id)
+    .bind(&params.q)
+    .bind(MATCH_START.to_string())
+    .bind(MATCH_END.to_string())
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    for m in &mut result {
+        m.headline = m.headline.as_deref().map(|headline| {
+            escape_html(headline)
+                .replace(MATCH_START, "<mark>")
+                .replace(MATCH_END, "</mark>")
+        });
+    }
+
+    Ok(Json(result))
+}
+
+// Private use characters, which nobody has any business putting in a
+// description
+const MATCH_START: char = '\u{E000}';
+const MATCH_END: char = '\u{E001}';
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Lists an outing's expenses in the order they were spent. Expenses spent on
+/// the same day are in the order they were added, then smallest first, so the
+/// order is stable from one page to the next. Transfers are left out unless
+/// the filters ask for them.
 async fn query_outing_expenses(
     pool: &PgPool,
     outing_id: OutingId,
+    filters: &ExpenseFilters,
 ) -> Result<Vec<Expense>, sqlx::Error> {
-    sqlx::query_as("SELECT * FROM expenses WHERE outing_id = $1")
-        .bind(outing_id)
-        .fetch_all(pool)
-        .await
+    sqlx::query_as(
+        "SELECT * FROM expenses \
+         WHERE outing_id = $1 \
+           AND ($2::date IS NULL OR incurred_at >= $2) \
+           AND ($3::date IS NULL OR incurred_at <= $3) \
+           AND ($4::text IS NULL OR person_name = $4) \
+           AND ($5::numeric IS NULL OR amount >= $5) \
+           AND ($6::numeric IS NULL OR amount <= $6) \
+           AND ($9 OR kind <> 'transfer') \
+         ORDER BY incurred_at, created_at, amount, expense_id \
+         LIMIT $7 OFFSET $8",
+    )
+    .bind(outing_id)
+    .bind(filters.from)
+    .bind(filters.to)
+    .bind(&filters.payer)
+    .bind(filters.min_amount)
+    .bind(filters.max_amount)
+    .bind(filters.limit)
+    .bind(filters.offset)
+    .bind(filters.include_transfers)
+    .fetch_all(pool)
+    .await
 }
 
 async fn retrieve_outing_expenses(
     Extension(pool): Extension<PgPool>,
     Path(outing_id): Path<OutingId>,
+    Query(filters): Query<ExpenseFilters>,
 ) -> Result<Json<Vec<Expense>>, (StatusCode, String)> {
-    let result = query_outing_expenses(&pool, outing_id)
+    if filters
+        .limit
+        .is_some_and(|limit| !(1..=MAX_PAGE_SIZE).contains(&limit))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("The limit must be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+    if filters.offset < 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The offset can't be negative".to_string(),
+        ));
+    }
+
+    let result = query_outing_expenses(&pool, outing_id, &filters)
         .await
         .map_err(internal_error)?;
 
     Ok(Json(result))
 }
 
+const TRANSFER_COLUMNS: &str = "expense_id AS transfer_id, created_at, incurred_at, outing_id, \
+     person_name AS \"from\", recipient AS \"to\", amount, description";
+
+async fn retrieve_outing_transfers(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+) -> Result<Json<Vec<Transfer>>, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    let result = sqlx::query_as(&format!(
+        "SELECT {} FROM expenses \
+         WHERE outing_id = $1 AND kind = 'transfer' \
+         ORDER BY incurred_at, created_at, expense_id",
+        TRANSFER_COLUMNS
+    ))
+    .bind(&outing_id)
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    Ok(Json(result))
+}
+
+/// Records money handed from one person in the outing to another. Just like
+/// transfers added as expenses, both people join the outing if they haven't
+/// already.
+async fn create_transfer(
+    Extension(pool): Extension<PgPool>,
+    Extension(hub): Extension<Hub>,
+    Path(outing_id): Path<OutingId>,
+    Json(payload): Json<TransferNew>,
+) -> Result<Json<Transfer>, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+    check_amount(&payload.amount).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    check_kind(
+        ExpenseKind::Transfer,
+        payload.amount,
+        &payload.from,
+        Some(&payload.to),
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    check_outing_unsettled(&mut tx, &outing_id).await?;
+
+    let joined = add_outing_people(&mut tx, &outing_id, &[&payload.from, &payload.to])
+        .await
+        .map_err(bad_request)?;
+
+    let result: Transfer = sqlx::query_as(&format!(
+        "INSERT INTO expenses(outing_id, person_name, recipient, amount, description, incurred_at, kind) \
+         VALUES ($1, $2, $3, $4, $5, $6, 'transfer') RETURNING {}",
+        TRANSFER_COLUMNS
+    ))
+    .bind(&outing_id)
+    .bind(&payload.from)
+    .bind(&payload.to)
+    .bind(payload.amount)
+    .bind(&payload.description)
+    .bind(payload.incurred_at)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(bad_request)?;
+
+    tx.commit().await.map_err(internal_error)?;
+
+    for name in joined {
+        hub.publish(outing_id.clone(), LiveMessage::PersonJoined { name });
+    }
+    hub.publish(
+        outing_id,
+        LiveMessage::TransferCreated {
+            transfer: result.clone(),
+        },
+    );
+
+    Ok(Json(result))
+}
+
 async fn list_outings(
     Extension(pool): Extension<PgPool>,
 ) -> Result<Json<Vec<Outing>>, (StatusCode, String)> {
@@ -161,140 +581,1551 @@ async fn list_outings(
     Ok(Json(result))
 }
 
+/// Turns away changes to outings that have already been settled, and stops
+/// the outing from being settled until the transaction is done.
+async fn check_outing_unsettled(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    outing_id: &OutingId,
+) -> Result<(), (StatusCode, String)> {
+    let settled: Option<(bool,)> =
+        sqlx::query_as("SELECT settled_at IS NOT NULL FROM outings WHERE outing_id = $1 FOR SHARE")
+            .bind(outing_id)
+            .fetch_optional(&mut **tx)
+            .await
+            .map_err(internal_error)?;
+
+    if let Some((true,)) = settled {
+        Err((
+            StatusCode::CONFLICT,
+            "Outing has already been settled".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Adds people to an outing unless they're in it already, returning just the
+/// ones who weren't, so that only they get announced as joining.
+async fn add_outing_people(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    outing_id: &OutingId,
+    names: &[&str],
+) -> Result<Vec<String>, sqlx::Error> {
+    let added: Vec<Named> = sqlx::query_as(
+        "INSERT INTO outing_people(outing_id, name) \
+         SELECT $1, unnest($2::text[]) \
+         ON CONFLICT DO NOTHING RETURNING name",
+    )
+    .bind(outing_id)
+    .bind(names)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(added.into_iter().map(|p| p.name).collect())
+}
+
 async fn create_expense(
     Extension(pool): Extension<PgPool>,
+    Extension(hub): Extension<Hub>,
     Json(payload): Json<ExpenseNew>,
 ) -> Result<Json<Expense>, (StatusCode, String)> {
-    let result = sqlx::query_as(
-        "WITH op AS ( \
-           INSERT INTO outing_people(outing_id, name) \
-           VALUES ($1, $2) \
-           ON CONFLICT DO NOTHING
-         ) \
-         INSERT INTO expenses(outing_id, person_name, amount, description) \
-         VALUES ($1, $2, $3, $4) RETURNING *",
+    check_amount(&payload.amount).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let (kind, amount) = check_kind(
+        payload.kind,
+        payload.amount,
+        &payload.person_name,
+        payload.recipient.as_deref(),
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    check_outing_unsettled(&mut tx, &payload.outing_id).await?;
+
+    // Both the payer and a transfer's recipient join the outing if they
+    // haven't already
+    let names: Vec<&str> = std::iter::once(payload.person_name.as_str())
+        .chain(payload.recipient.as_deref())
+        .collect();
+    let joined = add_outing_people(&mut tx, &payload.outing_id, &names)
+        .await
+        .map_err(bad_request)?;
+
+    let result: Expense = sqlx::query_as(
+        "INSERT INTO expenses(outing_id, person_name, amount, description, category, tags, incurred_at, kind, recipient) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *",
     )
     .bind(&payload.outing_id)
     .bind(&payload.person_name)
-    .bind(payload.amount)
+    .bind(amount)
     .bind(&payload.description)
-    .fetch_one(&pool)
+    .bind(normalize_category(payload.category))
+    .bind(normalize_tags(payload.tags))
+    .bind(payload.incurred_at)
+    .bind(kind)
+    .bind(&payload.recipient)
+    .fetch_one(&mut *tx)
     .await
     .map_err(bad_request)?;
 
+    tx.commit().await.map_err(internal_error)?;
+
+    for name in joined {
+        hub.publish(result.outing_id.clone(), LiveMessage::PersonJoined { name });
+    }
+    hub.publish(
+        result.outing_id.clone(),
+        LiveMessage::ExpenseCreated {
+            expense: result.clone(),
+        },
+    );
+
     Ok(Json(result))
 }
 
-async fn join_outing(
-    Extension(pool): Extension<PgPool>,
-    Path(outing_id): Path<OutingId>,
-    Json(payload): Json<Named>,
-) -> Result<StatusCode, (StatusCode, String)> {
-    sqlx::query(
-        "INSERT INTO outing_people(outing_id, name) \
-         VALUES ($1, $2) ON CONFLICT DO NOTHING", // conflicts should be no-ops
+/// Stores an itemized bill's lines and the shares derived from them. The bill
+/// must have passed `check_bill`.
+async fn insert_bill(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    expense_id: i32,
+    outing_id: &OutingId,
+    items: &[BillItem],
+    extras: &[BillExtra],
+) -> Result<Vec<ExpenseShare>, sqlx::Error> {
+    let lines = items
+        .iter()
+        .map(|i| (Some(&i.person_name), &i.description, i.amount))
+        .chain(extras.iter().map(|e| (None, &e.description, e.amount)));
+    for (line_no, (person_name, description, amount)) in lines.enumerate() {
+        sqlx::query(
+            "INSERT INTO expense_lines(expense_id, line_no, person_name, description, amount) \
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(expense_id)
+        .bind(line_no as i32)
+        .bind(person_name)
+        .bind(description)
+        .bind(amount)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    let shares = settle::itemize(expense_id, items, extras);
+    for share in &shares {
+        sqlx::query(
+            "INSERT INTO expense_shares(expense_id, outing_id, person_name, subtotal, extras, share) \
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(expense_id)
+        .bind(outing_id)
+        .bind(&share.person_name)
+        .bind(share.subtotal)
+        .bind(share.extras)
+        .bind(share.share)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    Ok(shares)
+}
+
+async fn query_outing_shares(
+    pool: &PgPool,
+    outing_id: &OutingId,
+) -> Result<Vec<ExpenseShare>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT * FROM expense_shares WHERE outing_id = $1 ORDER BY expense_id, person_name",
     )
-    .bind(&outing_id)
-    .bind(&payload.name)
-    .execute(&pool)
+    .bind(outing_id)
+    .fetch_all(pool)
     .await
-    .map_err(bad_request)?;
+}
 
-    Ok(StatusCode::NO_CONTENT)
+async fn query_bill_lines(
+    pool: &PgPool,
+    expense_id: i32,
+) -> Result<(Vec<BillItem>, Vec<BillExtra>), sqlx::Error> {
+    let items = sqlx::query_as(
+        "SELECT person_name, amount, description FROM expense_lines \
+         WHERE expense_id = $1 AND person_name IS NOT NULL ORDER BY line_no",
+    )
+    .bind(expense_id)
+    .fetch_all(pool)
+    .await?;
+    let extras = sqlx::query_as(
+        "SELECT amount, description FROM expense_lines \
+         WHERE expense_id = $1 AND person_name IS NULL ORDER BY line_no",
+    )
+    .bind(expense_id)
+    .fetch_all(pool)
+    .await?;
+    Ok((items, extras))
 }
 
-async fn finish_outing(
+/// Adds a bill where everybody pays for what they ordered, plus their part of
+/// the tax, tip and fees in proportion. Like any other expense, the payer
+/// joins the outing if they haven't already, but everybody who ordered
+/// something has to be in it already.
+async fn create_itemized_expense(
     Extension(pool): Extension<PgPool>,
-    Path(outing_id): Path<OutingId>,
-) -> Result<Json<Vec<OutingResult>>, (StatusCode, String)> {
-    // Here, a positive diff from avg indicates debt to the group, and negative
-    // means the person is owed by the group
-    let mut people_debts: VecDeque<PersonDiff> = VecDeque::from(
-        sqlx::query_as(
-            "WITH expenses_per_person AS ( \
-               SELECT op.name, COALESCE(SUM(ex.amount), 0) AS amount_paid \
-               FROM outing_people AS op \
-               LEFT JOIN expenses AS ex ON (op.outing_id = ex.outing_id AND op.name = ex.person_name) \
-               WHERE op.outing_id = $1 \
-               GROUP BY op.name \
-             ), group_avg AS ( \
-               SELECT AVG(amount_paid) FROM expenses_per_person
-             ) \
-             SELECT \
-               name, \
-               ROUND((SELECT avg FROM group_avg) - amount_paid, 4) AS diff_from_avg \
-             FROM expenses_per_person",
-        )
-        .bind(outing_id)
+    Extension(hub): Extension<Hub>,
+    Json(payload): Json<ItemizedExpenseNew>,
+) -> Result<Json<ItemizedExpense>, (StatusCode, String)> {
+    let total =
+        check_bill(&payload.items, &payload.extras).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let people: Vec<Named> = sqlx::query_as("SELECT name FROM outing_people WHERE outing_id = $1")
+        .bind(&payload.outing_id)
         .fetch_all(&pool)
         .await
-        .map_err(internal_error)?,
-    );
-
-    let mut results = Vec::with_capacity(people_debts.len());
-
-    while !people_debts.is_empty() {
-        // Sorts in ascending order, so the person with highest debt to the
-        // group comes at the back
-        people_debts
-            .make_contiguous()
-            .sort_unstable_by_key(|pd| pd.diff_from_avg);
-
-        let most_indebted = people_debts.pop_back().unwrap();
-
-        if people_debts.is_empty() {
-            if most_indebted.diff_from_avg > Decimal::new(1, 2) {
-                error!(
-                    "Somebody was left over with an oustanding balance greater than 1 cent, telling them to... pay themselves lol: {:?}",
-                    most_indebted
-                );
-                results.push(OutingResult {
-                    from: most_indebted.name.clone(),
-                    to: most_indebted.name,
-                    amount: most_indebted.diff_from_avg,
-                });
-            }
-        } else {
-            let most_owed = people_debts.front_mut().unwrap();
-            results.push(OutingResult {
-                from: most_indebted.name,
-                to: most_owed.name.clone(),
-                amount: most_indebted.diff_from_avg,
-            });
-            // This works because most_owed's diff should be negative, while
-            // most_indebted's diff should be positive.
-            most_owed.diff_from_avg += most_indebted.diff_from_avg;
-            if most_owed.diff_from_avg == Decimal::ZERO {
-                people_debts.pop_front();
-            }
+        .map_err(internal_error)?;
+    for item in &payload.items {
+        if item.person_name != payload.person_name
+            && !people.iter().any(|p| p.name == item.person_name)
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{} is not one of the outing's people", item.person_name),
+            ));
         }
     }
 
-    Ok(Json(results))
-}
+    let mut tx = pool.begin().await.map_err(internal_error)?;
 
-pub async fn migrate(pool: &PgPool) -> Result<(), sqlx::Error> {
-    info!("Updating database schema");
-    pool.execute(include_str!("../schema.sql")).await?;
-    Ok(())
-}
+    check_outing_unsettled(&mut tx, &payload.outing_id).await?;
 
-pub async fn app(pool: PgPool, js_build_dir: &str) -> Result<Router, shuttle_runtime::Error> {
-    info!("Building router");
-    let outing_routes = Router::new()
-        .route("/", get(list_outings).post(create_outing))
-        .route("/:id", get(retrieve_outing))
-        .route("/:id/balance", get(retrieve_outing_balance))
-        .route("/:id/expenses", get(retrieve_outing_expenses))
-        .route("/:id/finish", get(finish_outing))
-        .route("/:id/join", put(join_outing));
+    let joined = add_outing_people(&mut tx, &payload.outing_id, &[&payload.person_name])
+        .await
+        .map_err(bad_request)?;
 
-    let expense_routes = Router::new().route("/", post(create_expense));
+    let expense: Expense = sqlx::query_as(
+        "INSERT INTO expenses(outing_id, person_name, amount, description, category, tags, incurred_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+    )
+    .bind(&payload.outing_id)
+    .bind(&payload.person_name)
+    .bind(total)
+    .bind(&payload.description)
+    .bind(normalize_category(payload.category))
+    .bind(normalize_tags(payload.tags))
+    .bind(payload.incurred_at)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(bad_request)?;
+
+    let shares = insert_bill(
+        &mut tx,
+        expense.expense_id,
+        &expense.outing_id,
+        &payload.items,
+        &payload.extras,
+    )
+    .await
+    .map_err(bad_request)?;
+
+    tx.commit().await.map_err(internal_error)?;
+
+    for name in joined {
+        hub.publish(
+            expense.outing_id.clone(),
+            LiveMessage::PersonJoined { name },
+        );
+    }
+    hub.publish(
+        expense.outing_id.clone(),
+        LiveMessage::ExpenseCreated {
+            expense: expense.clone(),
+        },
+    );
+
+    Ok(Json(ItemizedExpense {
+        expense,
+        items: payload.items,
+        extras: payload.extras,
+        shares,
+    }))
+}
+
+async fn retrieve_expense_items(
+    Extension(pool): Extension<PgPool>,
+    Path(expense_id): Path<i32>,
+) -> Result<Json<ItemizedExpense>, (StatusCode, String)> {
+    let expense: Expense = sqlx::query_as("SELECT * FROM expenses WHERE expense_id = $1")
+        .bind(expense_id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                "Expense with given ID not found".to_string(),
+            )
+        })?;
+
+    let shares: Vec<ExpenseShare> =
+        sqlx::query_as("SELECT * FROM expense_shares WHERE expense_id = $1 ORDER BY person_name")
+            .bind(expense_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(internal_error)?;
+    if shares.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Expense with given ID isn't itemized".to_string(),
+        ));
+    }
+
+    let (items, extras) = query_bill_lines(&pool, expense_id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(ItemizedExpense {
+        expense,
+        items,
+        extras,
+        shares,
+    }))
+}
+
+/// Attaches a photo of the receipt to an expense, replacing any receipt it
+/// already had. Expects a multipart form with the file in its `receipt` field.
+async fn upload_receipt(
+    Extension(pool): Extension<PgPool>,
+    Extension(store): Extension<ReceiptStore>,
+    Path(expense_id): Path<i32>,
+    mut multipart: Multipart,
+) -> Result<Json<Receipt>, (StatusCode, String)> {
+    let outing_id: Option<(OutingId,)> =
+        sqlx::query_as("SELECT outing_id FROM expenses WHERE expense_id = $1")
+            .bind(expense_id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(internal_error)?;
+    let Some((outing_id,)) = outing_id else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Expense with given ID not found".to_string(),
+        ));
+    };
+
+    let multipart_error = |e: axum::extract::multipart::MultipartError| (e.status(), e.body_text());
+
+    let mut field = loop {
+        match multipart.next_field().await.map_err(multipart_error)? {
+            Some(field) if field.name() == Some("receipt") => break field,
+            Some(_) => continue,
+            None => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Expected the receipt in a field named receipt".to_string(),
+                ))
+            }
+        }
+    };
+
+    let content_type = field.content_type().unwrap_or_default().to_string();
+    if !receipts::is_supported(&content_type) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Receipts must be JPEG, PNG or WebP images, or PDFs".to_string(),
+        ));
+    }
+
+    let mut bytes = vec![];
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+        if bytes.len() + chunk.len() > MAX_RECEIPT_SIZE {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "Receipts can be at most {} MB",
+                    MAX_RECEIPT_SIZE / 1024 / 1024
+                ),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    if !receipts::looks_like(&content_type, &bytes) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("The receipt isn't a valid {} file", content_type),
+        ));
+    }
+
+    let key = ReceiptStore::key(outing_id, expense_id);
+    let size = bytes.len() as i32;
+    store
+        .put(&key, bytes, &content_type)
+        .await
+        .map_err(internal_error)?;
+
+    let result = sqlx::query_as(
+        "INSERT INTO receipts(expense_id, object_key, content_type, size) \
+         VALUES ($1, $2, $3, $4) \
+         ON CONFLICT (expense_id) DO UPDATE SET \
+           uploaded_at = CURRENT_TIMESTAMP, \
+           object_key = EXCLUDED.object_key, \
+           content_type = EXCLUDED.content_type, \
+           size = EXCLUDED.size \
+         RETURNING *",
+    )
+    .bind(expense_id)
+    .bind(&key)
+    .bind(&content_type)
+    .bind(size)
+    .fetch_one(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    Ok(Json(result))
+}
+
+/// Serves an expense's receipt through the API, whichever store it's in
+async fn retrieve_receipt(
+    Extension(pool): Extension<PgPool>,
+    Extension(store): Extension<ReceiptStore>,
+    Path(expense_id): Path<i32>,
+) -> Result<Response, (StatusCode, String)> {
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            "Receipt for given expense ID not found".to_string(),
+        )
+    };
+
+    let receipt: Receipt = sqlx::query_as("SELECT * FROM receipts WHERE expense_id = $1")
+        .bind(expense_id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+
+    let body = store
+        .get(&receipt.object_key)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, receipt.content_type),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+async fn join_outing(
+    Extension(pool): Extension<PgPool>,
+    Extension(hub): Extension<Hub>,
+    Path(outing_id): Path<OutingId>,
+    Json(payload): Json<Named>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query(
+        "INSERT INTO outing_people(outing_id, name) \
+         VALUES ($1, $2) ON CONFLICT DO NOTHING", // conflicts should be no-ops
+    )
+    .bind(&outing_id)
+    .bind(&payload.name)
+    .execute(&pool)
+    .await
+    .map_err(bad_request)?;
+
+    // Only tell everyone about people who weren't already here
+    if result.rows_affected() > 0 {
+        hub.publish(outing_id, LiveMessage::PersonJoined { name: payload.name });
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn import_outing_expenses(
+    Extension(pool): Extension<PgPool>,
+    Extension(hub): Extension<Hub>,
+    Path(outing_id): Path<OutingId>,
+    Query(params): Query<ImportParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<ImportReport>), (StatusCode, String)> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    let parsed = if content_type.starts_with("text/csv") {
+        import::parse_csv(&body)
+    } else if content_type.starts_with("application/json") {
+        import::parse_json(&body).map_err(|e| (StatusCode::BAD_REQUEST, e))?
+    } else {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Imports must be either text/csv or application/json".to_string(),
+        ));
+    };
+
+    check_outing_exists(&pool, &outing_id).await?;
+
+    let mut report = ImportReport {
+        dry_run: params.dry_run,
+        people_added: vec![],
+        expenses: vec![],
+        errors: vec![],
+    };
+
+    // Nothing gets written unless every single row is good
+    let rows = match import::validate(parsed) {
+        Ok(rows) => rows,
+        Err(errors) => {
+            report.errors = errors;
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(report)));
+        }
+    };
+
+    let existing: Vec<Named> =
+        sqlx::query_as("SELECT name FROM outing_people WHERE outing_id = $1")
+            .bind(&outing_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(internal_error)?;
+
+    for name in rows
+        .iter()
+        .flat_map(|row| std::iter::once(&row.person_name).chain(&row.recipient))
+    {
+        if !existing.iter().any(|p| &p.name == name) && !report.people_added.contains(name) {
+            report.people_added.push(name.clone());
+        }
+    }
+
+    if params.dry_run {
+        return Ok((StatusCode::OK, Json(report)));
+    }
+
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    check_outing_unsettled(&mut tx, &outing_id).await?;
+
+    let names: Vec<&str> = report.people_added.iter().map(String::as_str).collect();
+    let joined = add_outing_people(&mut tx, &outing_id, &names)
+        .await
+        .map_err(bad_request)?;
+
+    for row in rows {
+        let expense: Expense = sqlx::query_as(
+            "INSERT INTO expenses(outing_id, person_name, amount, description, category, tags, incurred_at, kind, recipient) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *",
+        )
+        .bind(&outing_id)
+        .bind(&row.person_name)
+        .bind(row.amount)
+        .bind(&row.description)
+        .bind(&row.category)
+        .bind(&row.tags)
+        .bind(row.incurred_at)
+        .bind(row.kind)
+        .bind(&row.recipient)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(bad_request)?;
+        report.expenses.push(expense);
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+
+    for name in joined {
+        hub.publish(outing_id.clone(), LiveMessage::PersonJoined { name });
+    }
+    for expense in &report.expenses {
+        hub.publish(
+            outing_id.clone(),
+            LiveMessage::ExpenseCreated {
+                expense: expense.clone(),
+            },
+        );
+    }
+
+    Ok((StatusCode::OK, Json(report)))
+}
+
+async fn export_outing_splitwise(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Query(params): Query<SplitwiseParams>,
+) -> Result<Response, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    // The people are the CSV's columns, and whoever comes first gets any
+    // cents left over from splitting an expense
+    let people: Vec<Named> =
+        sqlx::query_as("SELECT name FROM outing_people WHERE outing_id = $1 ORDER BY name")
+            .bind(&outing_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(internal_error)?;
+    let people: Vec<String> = people.into_iter().map(|p| p.name).collect();
+
+    let expenses = query_outing_expenses(&pool, outing_id.clone(), &ExpenseFilters::everything())
+        .await
+        .map_err(internal_error)?;
+
+    let settlements = if params.settled {
+        query_outing_results(&pool, outing_id.clone())
+            .await?
+            .results
+    } else {
+        vec![]
+    };
+
+    let shares = query_outing_shares(&pool, &outing_id)
+        .await
+        .map_err(internal_error)?;
+
+    let body = export::splitwise_csv(&people, &expenses, &shares, &settlements, &params.currency)
+        .map_err(internal_error)?;
+
+    Ok(csv_response(outing_id, "splitwise", body))
+}
+
+async fn export_outing_ledger(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Query(params): Query<LedgerParams>,
+) -> Result<Response, (StatusCode, String)> {
+    let outing: Option<Outing> = sqlx::query_as("SELECT * FROM outings WHERE outing_id = $1")
+        .bind(&outing_id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    let Some(outing) = outing else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Outing with given ID not found".to_string(),
+        ));
+    };
+
+    let people: Vec<Named> =
+        sqlx::query_as("SELECT name FROM outing_people WHERE outing_id = $1 ORDER BY name")
+            .bind(&outing_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(internal_error)?;
+    let people: Vec<String> = people.into_iter().map(|p| p.name).collect();
+
+    let expenses = query_outing_expenses(&pool, outing_id.clone(), &ExpenseFilters::everything())
+        .await
+        .map_err(internal_error)?;
+
+    let settlements = query_outing_results(&pool, outing_id.clone())
+        .await?
+        .results;
+
+    let shares = query_outing_shares(&pool, &outing_id)
+        .await
+        .map_err(internal_error)?;
+
+    let journal = export::ledger_journal(
+        &outing.name,
+        &people,
+        &expenses,
+        &shares,
+        &settlements,
+        &params,
+    );
+
+    Ok(download_response(
+        outing_id,
+        "ledger.journal",
+        "text/plain; charset=utf-8",
+        journal,
+    ))
+}
+
+async fn export_outing(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+) -> Result<Json<OutingArchive>, (StatusCode, String)> {
+    let outing: Option<Outing> = sqlx::query_as("SELECT * FROM outings WHERE outing_id = $1")
+        .bind(&outing_id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    let Some(outing) = outing else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Outing with given ID not found".to_string(),
+        ));
+    };
+
+    let people: Vec<Named> = sqlx::query_as("SELECT name FROM outing_people WHERE outing_id = $1")
+        .bind(&outing_id)
+        .fetch_all(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    let mut expenses: Vec<ArchivedExpense> = sqlx::query_as(
+        "SELECT expense_id, created_at, incurred_at, person_name, amount, description, category, \
+           tags, kind, recipient \
+         FROM expenses WHERE outing_id = $1 ORDER BY expense_id",
+    )
+    .bind(&outing_id)
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    for expense in &mut expenses {
+        (expense.items, expense.extras) = query_bill_lines(&pool, expense.expense_id)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    let settlements = query_outing_results(&pool, outing_id).await?.results;
+
+    Ok(Json(OutingArchive {
+        version: ARCHIVE_VERSION,
+        outing: ArchivedOuting {
+            name: outing.name,
+            created_at: outing.created_at,
+            rounding: outing.rounding,
+        },
+        people: people.into_iter().map(|p| p.name).collect(),
+        expenses,
+        settlements,
+    }))
+}
+
+async fn import_outing(
+    Extension(pool): Extension<PgPool>,
+    Json(mut archive): Json<OutingArchive>,
+) -> Result<Json<OutingDetails>, (StatusCode, String)> {
+    if !(1..=ARCHIVE_VERSION).contains(&archive.version) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unsupported archive version {}", archive.version),
+        ));
+    }
+
+    for expense in &mut archive.expenses {
+        if !archive.people.contains(&expense.person_name) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Expense paid by {} who is not one of the outing's people",
+                    expense.person_name
+                ),
+            ));
+        }
+        if let Some(recipient) = expense
+            .recipient
+            .as_ref()
+            .filter(|r| !archive.people.contains(r))
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Transfer to {} who is not one of the outing's people",
+                    recipient
+                ),
+            ));
+        }
+        check_amount(&expense.amount).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        (expense.kind, expense.amount) = check_kind(
+            expense.kind,
+            expense.amount,
+            &expense.person_name,
+            expense.recipient.as_deref(),
+        )
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+        if !expense.items.is_empty() {
+            let total = check_bill(&expense.items, &expense.extras)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            if total != expense.amount || expense.kind != ExpenseKind::Purchase {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Itemized expenses must be purchases whose items and extras add up to their amount"
+                        .to_string(),
+                ));
+            }
+            if let Some(item) = expense
+                .items
+                .iter()
+                .find(|i| !archive.people.contains(&i.person_name))
+            {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Item ordered by {} who is not one of the outing's people",
+                        item.person_name
+                    ),
+                ));
+            }
+        }
+    }
+
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let outing: Outing = sqlx::query_as(
+        "INSERT INTO outings(created_at, name, rounding) VALUES ($1, $2, $3) RETURNING *",
+    )
+    .bind(archive.outing.created_at)
+    .bind(&archive.outing.name)
+    .bind(archive.outing.rounding)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(bad_request)?;
+
+    for name in &archive.people {
+        sqlx::query(
+            "INSERT INTO outing_people(outing_id, name) \
+             VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(&outing.outing_id)
+        .bind(name)
+        .execute(&mut *tx)
+        .await
+        .map_err(bad_request)?;
+    }
+
+    for expense in &archive.expenses {
+        let (expense_id,): (i32,) = sqlx::query_as(
+            "INSERT INTO expenses(created_at, incurred_at, outing_id, person_name, amount, description, category, tags, kind, recipient) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING expense_id",
+        )
+        .bind(expense.created_at)
+        .bind(expense.incurred_at)
+        .bind(&outing.outing_id)
+        .bind(&expense.person_name)
+        .bind(expense.amount)
+        .bind(&expense.description)
+        .bind(normalize_category(expense.category.clone()))
+        .bind(normalize_tags(expense.tags.clone()))
+        .bind(expense.kind)
+        .bind(&expense.recipient)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(bad_request)?;
+
+        if !expense.items.is_empty() {
+            insert_bill(
+                &mut tx,
+                expense_id,
+                &outing.outing_id,
+                &expense.items,
+                &expense.extras,
+            )
+            .await
+            .map_err(bad_request)?;
+        }
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+
+    let people = archive
+        .people
+        .into_iter()
+        .map(|name| Named { name })
+        .collect();
+
+    Ok(Json(OutingDetails::new(outing, people)))
+}
+
+#[derive(Deserialize)]
+struct LiveParams {
+    last_event_id: Option<EventId>,
+}
+
+async fn outing_live(
+    ws: WebSocketUpgrade,
+    Extension(pool): Extension<PgPool>,
+    Extension(hub): Extension<Hub>,
+    Path(outing_id): Path<OutingId>,
+    Query(params): Query<LiveParams>,
+) -> Result<Response, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    Ok(ws.on_upgrade(move |socket| hub.serve(socket, outing_id, params.last_event_id)))
+}
+
+async fn query_outing_results(
+    pool: &PgPool,
+    outing_id: OutingId,
+) -> Result<OutingResults, (StatusCode, String)> {
+    query_preview_results(
+        pool,
+        outing_id,
+        &SettlementPreview::default(),
+        &FinishParams::default(),
+    )
+    .await
+}
+
+/// What everybody paid and how many expenses they paid for, as if the
+/// preview's expenses had been added and its excluded expenses removed.
+/// People who only appear in the preview's expenses are counted as having
+/// joined.
+///
+/// Refunds count as negative payments. Transfers aren't payments at all and
+/// are tallied separately, as what the sender handed over and what the
+/// recipient got, so they even out between the two of them and nobody else's
+/// share changes. Itemized bills are counted in full for the payer, with each
+/// person's share of them tallied on its own.
+async fn query_person_expenses(
+    executor: impl PgExecutor<'_>,
+    outing_id: OutingId,
+    preview: &SettlementPreview,
+) -> Result<Vec<PersonExpenses>, sqlx::Error> {
+    let mut names = vec![];
+    let mut amounts = vec![];
+    let mut kinds = vec![];
+    let mut recipients = vec![];
+    for expense in &preview.expenses {
+        names.push(expense.person_name.clone());
+        amounts.push(expense.amount);
+        kinds.push(expense.kind.to_string());
+        recipients.push(expense.recipient.clone());
+    }
+
+    sqlx::query_as(
+        "SELECT people.name, COALESCE(SUM(ex.amount), 0) AS amount_paid, \
+           COALESCE(SUM(ex.transferred), 0) AS transferred, \
+           COALESCE(SUM(ex.share), 0) AS itemized_share, COUNT(ex.paid) AS expense_count \
+         FROM ( \
+           SELECT name FROM outing_people WHERE outing_id = $1 \
+           UNION SELECT unnest($2::text[]) \
+           UNION SELECT name FROM unnest($6::text[]) AS name WHERE name IS NOT NULL \
+         ) AS people \
+         LEFT JOIN ( \
+           SELECT entry.* FROM ( \
+             SELECT person_name, amount, kind, recipient FROM expenses \
+             WHERE outing_id = $1 AND expense_id <> ALL($4) \
+             UNION ALL \
+             SELECT * FROM unnest($2::text[], $3::numeric[], $5::text[], $6::text[]) \
+               AS hypothetical(person_name, amount, kind, recipient) \
+           ) AS e \
+           CROSS JOIN LATERAL (VALUES \
+             (e.person_name, \
+               CASE e.kind WHEN 'refund' THEN -e.amount WHEN 'transfer' THEN 0 ELSE e.amount END, \
+               CASE WHEN e.kind <> 'transfer' THEN true END, \
+               CASE e.kind WHEN 'transfer' THEN e.amount ELSE 0 END, 0), \
+             (e.recipient, 0, NULL, -e.amount, 0) \
+           ) AS entry(person_name, amount, paid, transferred, share) \
+           UNION ALL \
+           SELECT person_name, 0, NULL, 0, share FROM expense_shares \
+           WHERE outing_id = $1 AND expense_id <> ALL($4) \
+         ) AS ex ON people.name = ex.person_name \
+         GROUP BY people.name \
+         ORDER BY people.name",
+    )
+    .bind(outing_id)
+    .bind(names)
+    .bind(amounts)
+    .bind(&preview.exclude)
+    .bind(kinds)
+    .bind(recipients)
+    .fetch_all(executor)
+    .await
+}
+
+/// What everybody paid towards the expenses that get split evenly. Itemized
+/// bills add up to their shares, so leaving both out keeps the even split to
+/// everything else. Transfers sum to zero, so counting them here only moves
+/// money between their sender and recipient.
+fn paid_from(people: &[PersonExpenses]) -> Vec<PersonPaid> {
+    people
+        .iter()
+        .map(|p| PersonPaid {
+            name: p.name.clone(),
+            amount_paid: p.amount_paid + p.transferred - p.itemized_share,
+        })
+        .collect()
+}
+
+/// Where everybody in an outing stands, rounded per the outing's policy.
+async fn query_outing_diffs(
+    conn: &mut PgConnection,
+    outing_id: OutingId,
+    preview: &SettlementPreview,
+) -> Result<(RoundingPolicy, Vec<PersonExpenses>, Vec<PersonDiff>), sqlx::Error> {
+    let rounding: Option<(RoundingPolicy,)> =
+        sqlx::query_as("SELECT rounding FROM outings WHERE outing_id = $1")
+            .bind(&outing_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+    let rounding = rounding.map(|(r,)| r).unwrap_or_default();
+
+    let people = query_person_expenses(&mut *conn, outing_id, preview).await?;
+    let diffs = settle::positions(&paid_from(&people), rounding);
+
+    Ok((rounding, people, diffs))
+}
+
+async fn query_preview_results(
+    pool: &PgPool,
+    outing_id: OutingId,
+    preview: &SettlementPreview,
+    params: &FinishParams,
+) -> Result<OutingResults, (StatusCode, String)> {
+    let constraints = query_outing_constraints(pool, &outing_id)
+        .await
+        .map_err(internal_error)?;
+
+    let mut conn = pool.acquire().await.map_err(internal_error)?;
+    let (rounding, people, diffs) = query_outing_diffs(&mut conn, outing_id, preview)
+        .await
+        .map_err(internal_error)?;
+
+    let explained = params.explain.then(|| settle::balances(people, &diffs));
+    let steps = match (params.strategy, &params.treasurer) {
+        (SettlementStrategy::Hub, Some(treasurer)) => {
+            let steps = settle::hub_steps(diffs, treasurer);
+            let forbidden = steps.iter().find(|s| {
+                constraints.iter().any(|c| {
+                    c.kind == ConstraintKind::Forbidden
+                        && c.payer == s.transfer.from
+                        && c.payee == s.transfer.to
+                })
+            });
+            if let Some(step) = forbidden {
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!(
+                        "{} can't pay {}, so they can't settle up through {}",
+                        step.transfer.from, step.transfer.to, treasurer
+                    ),
+                ));
+            }
+            steps
+        }
+        _ if constraints.is_empty() => settle::greedy_steps(diffs),
+        _ => settle::constrained_steps(diffs, &constraints).map_err(|stuck| {
+            let owing: Vec<String> = stuck
+                .iter()
+                .map(|pd| format!("{} would still owe {}", pd.name, pd.diff_from_avg))
+                .collect();
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!(
+                    "The outing's constraints make it impossible to settle up: {}",
+                    owing.join(", ")
+                ),
+            )
+        })?,
+    };
+
+    Ok(OutingResults {
+        rounding,
+        results: steps.iter().map(|s| s.transfer.clone()).collect(),
+        explanation: explained.map(|people| Explanation { people, steps }),
+    })
+}
+
+async fn finish_outing(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Query(params): Query<FinishParams>,
+) -> Result<Json<OutingResults>, (StatusCode, String)> {
+    if params.strategy == SettlementStrategy::Hub {
+        let Some(treasurer) = &params.treasurer else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "The hub strategy needs a treasurer".to_string(),
+            ));
+        };
+        let member: Option<(String,)> =
+            sqlx::query_as("SELECT name FROM outing_people WHERE outing_id = $1 AND name = $2")
+                .bind(&outing_id)
+                .bind(treasurer)
+                .fetch_optional(&pool)
+                .await
+                .map_err(internal_error)?;
+        if member.is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{} is not one of the outing's people", treasurer),
+            ));
+        }
+    }
+
+    let results =
+        query_preview_results(&pool, outing_id, &SettlementPreview::default(), &params).await?;
+
+    Ok(Json(results))
+}
+
+async fn preview_finish_outing(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+    Json(mut preview): Json<SettlementPreview>,
+) -> Result<Json<OutingResults>, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    for expense in &mut preview.expenses {
+        if expense.outing_id != outing_id {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Previewed expenses must belong to the outing being previewed".to_string(),
+            ));
+        }
+        if expense.person_name.trim().is_empty() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "person_name must not be blank".to_string(),
+            ));
+        }
+        check_amount(&expense.amount).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        (expense.kind, expense.amount) = check_kind(
+            expense.kind,
+            expense.amount,
+            &expense.person_name,
+            expense.recipient.as_deref(),
+        )
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
+
+    // Excluding an expense that isn't there is almost certainly a mistake,
+    // and quietly previewing without it would give a misleading answer
+    let found: Vec<(i32,)> = sqlx::query_as(
+        "SELECT expense_id FROM expenses WHERE outing_id = $1 AND expense_id = ANY($2)",
+    )
+    .bind(&outing_id)
+    .bind(&preview.exclude)
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+    if let Some(missing) = preview
+        .exclude
+        .iter()
+        .find(|id| !found.iter().any(|(f,)| f == *id))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Expense {} is not part of this outing", missing),
+        ));
+    }
+
+    let results =
+        query_preview_results(&pool, outing_id, &preview, &FinishParams::default()).await?;
+
+    Ok(Json(results))
+}
+
+async fn create_group(
+    Extension(pool): Extension<PgPool>,
+    Json(payload): Json<GroupNew>,
+) -> Result<Json<GroupDetails>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let group: Group = sqlx::query_as("INSERT INTO groups(name) VALUES ($1) RETURNING *")
+        .bind(&payload.name)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(bad_request)?;
+
+    let mut members: Vec<Named> = vec![];
+    for name in payload.members {
+        let name = name.trim().to_string();
+        if name.is_empty() || members.iter().any(|m| m.name == name) {
+            continue;
+        }
+        sqlx::query("INSERT INTO group_members(group_id, name) VALUES ($1, $2)")
+            .bind(&group.group_id)
+            .bind(&name)
+            .execute(&mut *tx)
+            .await
+            .map_err(bad_request)?;
+        members.push(Named { name });
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(GroupDetails::new(group, members, vec![])))
+}
+
+async fn join_group(
+    Extension(pool): Extension<PgPool>,
+    Path(group_id): Path<GroupId>,
+    Json(payload): Json<Named>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    sqlx::query(
+        "INSERT INTO group_members(group_id, name) \
+         VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(&group_id)
+    .bind(&payload.name)
+    .execute(&pool)
+    .await
+    .map_err(bad_request)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_groups(
+    Extension(pool): Extension<PgPool>,
+) -> Result<Json<Vec<Group>>, (StatusCode, String)> {
+    let result = sqlx::query_as("SELECT * FROM groups LIMIT 500")
+        .fetch_all(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(result))
+}
+
+async fn retrieve_group(
+    Extension(pool): Extension<PgPool>,
+    Path(group_id): Path<GroupId>,
+) -> Result<Json<GroupDetails>, (StatusCode, String)> {
+    let group = sqlx::query_as("SELECT * FROM groups WHERE group_id = $1")
+        .bind(&group_id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    let Some(group) = group else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Group with given ID not found".to_string(),
+        ));
+    };
+
+    let members = sqlx::query_as("SELECT name FROM group_members WHERE group_id = $1")
+        .bind(&group_id)
+        .fetch_all(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    let outings = sqlx::query_as("SELECT * FROM outings WHERE group_id = $1 ORDER BY outing_id")
+        .bind(&group_id)
+        .fetch_all(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(GroupDetails::new(group, members, outings)))
+}
+
+async fn check_group_exists(
+    executor: impl PgExecutor<'_>,
+    group_id: &GroupId,
+) -> Result<(), (StatusCode, String)> {
+    let exists: Option<(i32,)> = sqlx::query_as("SELECT group_id FROM groups WHERE group_id = $1")
+        .bind(group_id)
+        .fetch_optional(executor)
+        .await
+        .map_err(internal_error)?;
+
+    if exists.is_some() {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            "Group with given ID not found".to_string(),
+        ))
+    }
+}
+
+async fn create_recurring_expense(
+    Extension(pool): Extension<PgPool>,
+    Path(group_id): Path<GroupId>,
+    Json(payload): Json<RecurringExpenseNew>,
+) -> Result<Json<RecurringExpense>, (StatusCode, String)> {
+    check_amount(&payload.amount).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    // Every occurrence is added as a purchase, which can't be negative
+    if payload.amount.is_sign_negative() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Recurring expenses must not have negative amounts".to_string(),
+        ));
+    }
+    check_group_exists(&pool, &group_id).await?;
+
+    let result = sqlx::query_as(
+        "WITH gm AS ( \
+           INSERT INTO group_members(group_id, name) \
+           VALUES ($1, $2) \
+           ON CONFLICT DO NOTHING \
+         ) \
+         INSERT INTO recurring_expenses(group_id, person_name, amount, description, schedule, starts_on) \
+         VALUES ($1, $2, $3, $4, $5, COALESCE($6, CURRENT_DATE)) RETURNING *",
+    )
+    .bind(&group_id)
+    .bind(&payload.person_name)
+    .bind(payload.amount)
+    .bind(&payload.description)
+    .bind(payload.schedule)
+    .bind(payload.starts_on)
+    .fetch_one(&pool)
+    .await
+    .map_err(bad_request)?;
+
+    Ok(Json(result))
+}
+
+async fn list_recurring_expenses(
+    Extension(pool): Extension<PgPool>,
+    Path(group_id): Path<GroupId>,
+) -> Result<Json<Vec<RecurringExpense>>, (StatusCode, String)> {
+    check_group_exists(&pool, &group_id).await?;
+
+    let result = sqlx::query_as(
+        "SELECT * FROM recurring_expenses WHERE group_id = $1 ORDER BY recurring_id",
+    )
+    .bind(&group_id)
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    Ok(Json(result))
+}
+
+/// Stops a recurring expense from coming around again. Outings it already
+/// added are left alone.
+async fn delete_recurring_expense(
+    Extension(pool): Extension<PgPool>,
+    Path((group_id, recurring_id)): Path<(GroupId, i32)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result =
+        sqlx::query("DELETE FROM recurring_expenses WHERE group_id = $1 AND recurring_id = $2")
+            .bind(&group_id)
+            .bind(recurring_id)
+            .execute(&pool)
+            .await
+            .map_err(internal_error)?;
+
+    if result.rows_affected() == 0 {
+        Err((
+            StatusCode::NOT_FOUND,
+            "Recurring expense with given ID not found".to_string(),
+        ))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+/// Nets everybody's positions across all of a group's unsettled outings, then
+/// settles the totals the same way a single outing would be. Each outing is
+/// rounded per its own policy first. Outings' constraints only apply to the
+/// outings themselves, so they aren't taken into account here.
+///
+/// With `lock`, the outings stay locked until the end of the transaction, so
+/// that nothing can be added to them in the meantime.
+async fn query_group_results(
+    conn: &mut PgConnection,
+    group_id: &GroupId,
+    lock: bool,
+) -> Result<GroupResults, (StatusCode, String)> {
+    check_group_exists(&mut *conn, group_id).await?;
+
+    let outings: Vec<(OutingId,)> = sqlx::query_as(&format!(
+        "SELECT outing_id FROM outings \
+         WHERE group_id = $1 AND settled_at IS NULL ORDER BY outing_id{}",
+        if lock { " FOR UPDATE" } else { "" }
+    ))
+    .bind(group_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+    let outings: Vec<OutingId> = outings.into_iter().map(|(id,)| id).collect();
+
+    let mut diffs = Vec::with_capacity(outings.len());
+    for outing_id in &outings {
+        let (_, _, outing_diffs) =
+            query_outing_diffs(conn, outing_id.clone(), &SettlementPreview::default())
+                .await
+                .map_err(internal_error)?;
+        diffs.push(outing_diffs);
+    }
+
+    let steps = settle::greedy_steps(settle::net(diffs));
+
+    Ok(GroupResults {
+        outings,
+        results: steps.into_iter().map(|s| s.transfer).collect(),
+    })
+}
+
+async fn finish_group(
+    Extension(pool): Extension<PgPool>,
+    Path(group_id): Path<GroupId>,
+) -> Result<Json<GroupResults>, (StatusCode, String)> {
+    let mut conn = pool.acquire().await.map_err(internal_error)?;
+    let results = query_group_results(&mut conn, &group_id, false).await?;
+
+    Ok(Json(results))
+}
+
+/// Marks every outing that went into the group's combined settlement as
+/// settled, so they won't be counted again next time. The outings are locked
+/// while the settlement is worked out, so whatever gets added to them either
+/// makes it into the settlement or is turned away because they're settled.
+async fn settle_group(
+    Extension(pool): Extension<PgPool>,
+    Path(group_id): Path<GroupId>,
+) -> Result<Json<GroupResults>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let results = query_group_results(&mut tx, &group_id, true).await?;
+
+    sqlx::query("UPDATE outings SET settled_at = CURRENT_TIMESTAMP WHERE outing_id = ANY($1)")
+        .bind(&results.outings)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(results))
+}
+
+fn download_response(
+    outing_id: OutingId,
+    file_name: &str,
+    content_type: &str,
+    body: impl IntoResponse,
+) -> Response {
+    let disposition = format!(
+        "attachment; filename=\"{}-{}\"",
+        String::from(outing_id),
+        file_name
+    );
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+fn csv_response(outing_id: OutingId, name: &str, body: Vec<u8>) -> Response {
+    download_response(
+        outing_id,
+        &format!("{}.csv", name),
+        "text/csv; charset=utf-8",
+        body,
+    )
+}
+
+async fn export_outing_expenses_csv(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+) -> Result<Response, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    let expenses = query_outing_expenses(&pool, outing_id.clone(), &ExpenseFilters::everything())
+        .await
+        .map_err(internal_error)?;
+    let body = export::expenses_csv(&expenses).map_err(internal_error)?;
+
+    Ok(csv_response(outing_id, "expenses", body))
+}
+
+async fn export_outing_results_csv(
+    Extension(pool): Extension<PgPool>,
+    Path(outing_id): Path<OutingId>,
+) -> Result<Response, (StatusCode, String)> {
+    check_outing_exists(&pool, &outing_id).await?;
+
+    let results = query_outing_results(&pool, outing_id.clone()).await?;
+    let body = export::results_csv(&results.results).map_err(internal_error)?;
+
+    Ok(csv_response(outing_id, "finish", body))
+}
+
+/// Middleware which notes whether the request wants amounts in the legacy
+/// float format, i.e. whether any of its `Accept` media ranges carry an
+/// `amounts=float` parameter.
+async fn amount_format(req: Request, next: Next) -> Response {
+    let float = req
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .flat_map(|range| range.split(';').skip(1))
+        .any(|param| param.trim().eq_ignore_ascii_case("amounts=float"));
+
+    FLOAT_AMOUNTS.scope(float, next.run(req)).await
+}
+
+pub async fn migrate(pool: &PgPool) -> Result<(), sqlx::Error> {
+    info!("Updating database schema");
+    pool.execute(include_str!("../schema.sql")).await?;
+    Ok(())
+}
+
+pub async fn app(
+    pool: PgPool,
+    receipts: ReceiptStore,
+    js_build_dir: &str,
+) -> Result<Router, shuttle_runtime::Error> {
+    info!("Building router");
+    let outing_routes = Router::new()
+        .route("/", get(list_outings).post(create_outing))
+        .route("/import", post(import_outing))
+        .route("/:id", get(retrieve_outing))
+        .route("/:id/balance", get(retrieve_outing_balance))
+        .route(
+            "/:id/constraints",
+            get(retrieve_outing_constraints).put(update_outing_constraints),
+        )
+        .route("/:id/expenses", get(retrieve_outing_expenses))
+        .route("/:id/expenses.csv", get(export_outing_expenses_csv))
+        .route("/:id/export", get(export_outing))
+        .route("/:id/export/ledger", get(export_outing_ledger))
+        .route("/:id/export/splitwise", get(export_outing_splitwise))
+        .route("/:id/finish", get(finish_outing))
+        .route("/:id/finish.csv", get(export_outing_results_csv))
+        .route("/:id/finish/preview", post(preview_finish_outing))
+        .route("/:id/group", put(update_outing_group))
+        .route("/:id/import", post(import_outing_expenses))
+        .route("/:id/join", put(join_outing))
+        .route("/:id/live", get(outing_live))
+        .route("/:id/people", get(retrieve_outing_people))
+        .route("/:id/rounding", put(update_outing_rounding))
+        .route("/:id/search", get(search_outing_expenses))
+        .route("/:id/summary", get(retrieve_outing_summary))
+        .route(
+            "/:id/transfers",
+            get(retrieve_outing_transfers).post(create_transfer),
+        );
+
+    let expense_routes = Router::new()
+        .route("/", post(create_expense))
+        .route("/itemized", post(create_itemized_expense))
+        .route("/:id/items", get(retrieve_expense_items))
+        .route(
+            "/:id/receipt",
+            get(retrieve_receipt)
+                .post(upload_receipt)
+                // Leave room for the rest of the multipart form, so that
+                // receipts which are too big get a more helpful error
+                .layer(DefaultBodyLimit::max(MAX_RECEIPT_SIZE + 64 * 1024)),
+        );
+
+    let group_routes = Router::new()
+        .route("/", get(list_groups).post(create_group))
+        .route("/:id", get(retrieve_group))
+        .route("/:id/finish", get(finish_group))
+        .route("/:id/join", put(join_group))
+        .route(
+            "/:id/recurring",
+            get(list_recurring_expenses).post(create_recurring_expense),
+        )
+        .route(
+            "/:id/recurring/:recurring_id",
+            delete(delete_recurring_expense),
+        )
+        .route("/:id/settle", post(settle_group));
 
     let api_routes = Router::new()
         .route("/ping", get(|| async { "pong" }))
         .nest("/outings", outing_routes)
-        .nest("/expenses", expense_routes);
+        .nest("/expenses", expense_routes)
+        .nest("/groups", group_routes)
+        .layer(middleware::from_fn(amount_format));
 
     let router = Router::new()
         .nest("/api", api_routes)
@@ -308,6 +2139,8 @@ pub async fn app(pool: PgPool, js_build_dir: &str) -> Result<Router, shuttle_run
             }),
         )
         .layer(Extension(pool))
+        .layer(Extension(receipts))
+        .layer(Extension(Hub::default()))
         .layer(TraceLayer::new_for_http());
 
     Ok(router)
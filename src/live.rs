@@ -0,0 +1,266 @@
+/*
+ * Copyright © 2023 Jonathan Ming
+ *
+ * This file is part of Birdie.
+ *
+ * For information about warranty and licensing, see the disclaimer in
+ * src/lib.rs as well as the LICENSE file.
+ */
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::ws::{Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+use crate::models::{Expense, OutingId, Transfer};
+
+/// How many events each outing keeps around for clients that reconnect with a
+/// `last_event_id`. Anybody who has missed more than this gets told to resync.
+const BACKLOG_LEN: usize = 100;
+
+/// How long an outing's channel, and its backlog, are kept after the last
+/// client watching it goes away, so that it can reconnect without missing
+/// anything.
+const IDLE_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// Capacity of each outing's broadcast channel. Subscribers that fall further
+/// behind than this get lagged, which we also treat as a resync.
+const CHANNEL_CAPACITY: usize = 64;
+
+pub type EventId = u64;
+
+/// Messages the server pushes to everybody watching an outing.
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveMessage {
+    PersonJoined {
+        name: String,
+    },
+    ExpenseCreated {
+        expense: Expense,
+    },
+    TransferCreated {
+        transfer: Transfer,
+    },
+    // Presence is ephemeral: it gets broadcast, but it is never replayed to
+    // reconnecting clients since it's probably stale by then anyway
+    Presence {
+        name: String,
+        activity: Option<String>,
+    },
+    PresenceLeft {
+        name: String,
+    },
+    // Tells the client it missed events we can no longer replay, so it should
+    // refetch the outing from the REST API
+    Resync,
+}
+
+impl LiveMessage {
+    fn is_ephemeral(&self) -> bool {
+        matches!(
+            self,
+            LiveMessage::Presence { .. } | LiveMessage::PresenceLeft { .. }
+        )
+    }
+}
+
+/// Messages clients are allowed to send us. Everything else about an outing
+/// changes through the REST API.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Presence {
+        name: String,
+        activity: Option<String>,
+    },
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct LiveEvent {
+    pub id: EventId,
+    #[serde(flatten)]
+    pub message: LiveMessage,
+}
+
+struct Channel {
+    sender: broadcast::Sender<LiveEvent>,
+    // ID of the newest event sent on this channel
+    last_id: EventId,
+    backlog: VecDeque<LiveEvent>,
+    // ID of the newest event that has fallen out of the backlog, or that
+    // happened before the channel was opened
+    evicted_through: EventId,
+    // When the last client watching the outing went away
+    idle_since: Option<Instant>,
+}
+
+impl Channel {
+    fn new(last_id: EventId) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            last_id,
+            backlog: VecDeque::with_capacity(BACKLOG_LEN),
+            evicted_through: last_id,
+            idle_since: None,
+        }
+    }
+
+    fn expired(&self) -> bool {
+        self.idle_since
+            .is_some_and(|since| since.elapsed() >= IDLE_GRACE)
+    }
+}
+
+/// Per-outing broadcast hub shared by every WebSocket connection and every
+/// route that changes an outing.
+///
+/// An outing only has a channel while somebody is watching it, or has been
+/// within the last `IDLE_GRACE`. Event IDs are shared between all outings, so
+/// that IDs from a channel that has since been closed are never mistaken for
+/// ones from a newer channel.
+#[derive(Clone, Default)]
+pub struct Hub {
+    outings: Arc<Mutex<HashMap<OutingId, Channel>>>,
+    last_id: Arc<AtomicU64>,
+}
+
+impl Hub {
+    pub fn publish(&self, outing_id: OutingId, message: LiveMessage) {
+        let mut outings = self.outings.lock().unwrap();
+        // The ID gets used up even when nobody is watching, so anybody who
+        // reconnects later can tell they missed something
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let Some(channel) = outings.get_mut(&outing_id) else {
+            return;
+        };
+
+        let event = LiveEvent { id, message };
+        channel.last_id = id;
+
+        if !event.message.is_ephemeral() {
+            if channel.backlog.len() == BACKLOG_LEN {
+                if let Some(evicted) = channel.backlog.pop_front() {
+                    channel.evicted_through = evicted.id;
+                }
+            }
+            channel.backlog.push_back(event.clone());
+        }
+
+        // An error here just means nobody is listening right now
+        let _ = channel.sender.send(event);
+    }
+
+    /// Subscribes to an outing's events, returning whatever the caller missed
+    /// since `last_event_id` along with the receiver for new ones. Both are
+    /// taken under the same lock so that no event can slip between them.
+    fn subscribe(
+        &self,
+        outing_id: OutingId,
+        last_event_id: Option<EventId>,
+    ) -> (Vec<LiveEvent>, broadcast::Receiver<LiveEvent>) {
+        let mut outings = self.outings.lock().unwrap();
+        outings.retain(|_, channel| !channel.expired());
+        let channel = outings
+            .entry(outing_id)
+            .or_insert_with(|| Channel::new(self.last_id.load(Ordering::Relaxed)));
+        channel.idle_since = None;
+
+        let missed = match last_event_id {
+            None => vec![],
+            Some(last) if last == channel.last_id => vec![],
+            Some(last) if last < channel.evicted_through || last > channel.last_id => {
+                vec![LiveEvent {
+                    id: last,
+                    message: LiveMessage::Resync,
+                }]
+            }
+            Some(last) => channel
+                .backlog
+                .iter()
+                .filter(|e| e.id > last)
+                .cloned()
+                .collect(),
+        };
+
+        (missed, channel.sender.subscribe())
+    }
+
+    /// Starts the clock on closing an outing's channel once nobody is watching
+    /// it anymore, and closes any that have been idle for long enough.
+    fn unsubscribe(&self, outing_id: &OutingId, receiver: broadcast::Receiver<LiveEvent>) {
+        let mut outings = self.outings.lock().unwrap();
+        drop(receiver);
+        if let Some(channel) = outings.get_mut(outing_id) {
+            if channel.sender.receiver_count() == 0 {
+                channel.idle_since = Some(Instant::now());
+            }
+        }
+        outings.retain(|_, channel| !channel.expired());
+    }
+
+    pub async fn serve(
+        self,
+        mut socket: WebSocket,
+        outing_id: OutingId,
+        last_event_id: Option<EventId>,
+    ) {
+        let (missed, mut receiver) = self.subscribe(outing_id.clone(), last_event_id);
+        let mut last_sent = last_event_id.unwrap_or(0);
+        for event in missed {
+            if send_event(&mut socket, &event).await.is_err() {
+                self.unsubscribe(&outing_id, receiver);
+                return;
+            }
+            last_sent = event.id;
+        }
+
+        // The name this connection last announced presence as, so we can tell
+        // everyone else when it goes away
+        let mut present_as: Option<String> = None;
+
+        loop {
+            tokio::select! {
+                event = receiver.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(_)) => LiveEvent { id: last_sent, message: LiveMessage::Resync },
+                        Err(RecvError::Closed) => break,
+                    };
+                    if send_event(&mut socket, &event).await.is_err() {
+                        break;
+                    }
+                    last_sent = event.id;
+                }
+                msg = socket.recv() => {
+                    match msg {
+                        Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                            Ok(ClientMessage::Presence { name, activity }) => {
+                                present_as = Some(name.clone());
+                                self.publish(outing_id.clone(), LiveMessage::Presence { name, activity });
+                            }
+                            Err(e) => debug!("Ignoring unrecognized live message: {}", e),
+                        },
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    }
+                }
+            }
+        }
+
+        if let Some(name) = present_as {
+            self.publish(outing_id.clone(), LiveMessage::PresenceLeft { name });
+        }
+        self.unsubscribe(&outing_id, receiver);
+    }
+}
+
+async fn send_event(socket: &mut WebSocket, event: &LiveEvent) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(event).expect("live events always serialize");
+    socket.send(Message::Text(text)).await
+}
@@ -18,6 +18,7 @@
  * Birdie. If not, see <https://www.gnu.org/licenses/>.
  */
 
+use birdie::receipts::ReceiptStore;
 use shuttle_axum::{AxumService, ShuttleAxum};
 use shuttle_runtime::CustomError;
 use shuttle_secrets::SecretStore;
@@ -28,6 +29,11 @@ async fn axum(
     #[shuttle_shared_db::Postgres] pool: PgPool,
     #[shuttle_secrets::Secrets] secret_store: SecretStore,
 ) -> ShuttleAxum {
+    let receipts = match secret_store.get("RECEIPTS_BUCKET") {
+        Some(bucket) => ReceiptStore::s3(&secret_store, bucket).await?,
+        None => ReceiptStore::local("./receipts"),
+    };
+
     let frontend_dir = if std::env::var("BIRDIE_LOCAL").is_err() {
         let dir = "./frontend";
         birdie::unpack_frontend(secret_store, dir).await?;
@@ -38,5 +44,9 @@ async fn axum(
 
     birdie::migrate(&pool).await.map_err(CustomError::new)?;
 
-    birdie::app(pool, frontend_dir).await.map(AxumService::from)
+    tokio::spawn(birdie::recurring::run(pool.clone()));
+
+    birdie::app(pool, receipts, frontend_dir)
+        .await
+        .map(AxumService::from)
 }
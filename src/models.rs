@@ -7,8 +7,8 @@
  * src/lib.rs as well as the LICENSE file.
  */
 use harsh::Harsh;
-use serde::{Deserialize, Serialize};
-use sqlx::types::chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::types::chrono::{DateTime, NaiveDate, Utc};
 use sqlx::types::Decimal;
 use sqlx::FromRow;
 use std::fmt::Display;
@@ -22,6 +22,28 @@ lazy_static! {
         .unwrap();
 }
 
+tokio::task_local! {
+    /// Whether the current request asked for amounts as JSON numbers rather
+    /// than strings. See `serialize_amount`.
+    pub static FLOAT_AMOUNTS: bool;
+}
+
+/// Serializes money as an exact decimal string, like `"24.6500"`. Amounts used
+/// to be serialized as floats, which could turn them into things like
+/// `2.7775000000000003`, so requests can still ask for that format with an
+/// `Accept: application/json; amounts=float` header until every client has
+/// moved off it.
+pub fn serialize_amount<S>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if FLOAT_AMOUNTS.try_with(|f| *f).unwrap_or(false) {
+        rust_decimal::serde::float::serialize(amount, serializer)
+    } else {
+        serializer.serialize_str(&amount.to_string())
+    }
+}
+
 #[derive(Debug)]
 pub enum IdParseError {
     Harsh(harsh::Error),
@@ -59,7 +81,7 @@ impl From<harsh::Error> for IdParseError {
 // The second serde macro `into` says: When serializing, always convert the
 // OutingId to a String using my custom impl From<OutingId> for String, then
 // serialize that String
-#[derive(Clone, Serialize, Deserialize, sqlx::Type)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize, sqlx::Type)]
 #[serde(try_from = "String")]
 #[serde(into = "String")]
 #[sqlx(transparent)] // have sqlx transparently encode/decode this type using the i32 impl
@@ -94,10 +116,68 @@ impl From<OutingId> for String {
     }
 }
 
+/// Groups get hashid IDs just like outings, for all the same reasons. See
+/// `OutingId`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize, sqlx::Type)]
+#[serde(try_from = "String")]
+#[serde(into = "String")]
+#[sqlx(transparent)]
+pub struct GroupId(i32);
+
+impl TryFrom<String> for GroupId {
+    type Error = IdParseError;
+    fn try_from(s: String) -> Result<GroupId, IdParseError> {
+        let res = HARSH.decode(s.trim().to_uppercase())?;
+        if res.len() != 1 || res[0] > i32::MAX as u64 {
+            Err(IdParseError::User("Invalid group ID provided"))
+        } else {
+            Ok(GroupId(res[0] as i32))
+        }
+    }
+}
+
+impl From<GroupId> for String {
+    fn from(input: GroupId) -> String {
+        HARSH.encode(&[input.0 as u64])
+    }
+}
+
+/// How an outing's settlement amounts get rounded. See `settle::positions`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum RoundingPolicy {
+    /// Whole cents, with leftover cents going to whoever lost the most in
+    /// rounding
+    #[default]
+    Cent,
+    /// Whole cents, rounding halves to the nearest even cent
+    Bankers,
+    /// Whole cents, with everybody who owes money rounding up
+    PayersUp,
+    /// The nearest 5 cents, for groups settling up in cash
+    Nickel,
+    /// Whole dollars (or euros, or...)
+    Whole,
+}
+
 #[derive(Deserialize)]
 pub struct OutingNew {
     pub name: String,
     pub person_name: String, // Becomes an OutingPerson
+    #[serde(default)]
+    pub rounding: RoundingPolicy,
+    pub group_id: Option<GroupId>,
+}
+
+#[derive(Deserialize)]
+pub struct RoundingUpdate {
+    pub rounding: RoundingPolicy,
+}
+
+#[derive(Deserialize)]
+pub struct OutingGroupUpdate {
+    pub group_id: Option<GroupId>,
 }
 
 #[derive(Serialize, FromRow)]
@@ -105,6 +185,10 @@ pub struct Outing {
     pub outing_id: OutingId,
     pub created_at: DateTime<Utc>,
     pub name: String,
+    pub rounding: RoundingPolicy,
+    pub group_id: Option<GroupId>,
+    // When the outing was settled up as part of its group
+    pub settled_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize, Serialize, FromRow, PartialEq, Eq, Debug)]
@@ -117,6 +201,9 @@ pub struct OutingDetails {
     pub outing_id: OutingId,
     pub created_at: DateTime<Utc>,
     pub name: String,
+    pub rounding: RoundingPolicy,
+    pub group_id: Option<GroupId>,
+    pub settled_at: Option<DateTime<Utc>>,
     pub people: Vec<String>,
 }
 
@@ -126,26 +213,152 @@ impl OutingDetails {
             outing_id: outing.outing_id,
             created_at: outing.created_at,
             name: outing.name,
+            rounding: outing.rounding,
+            group_id: outing.group_id,
+            settled_at: outing.settled_at,
             people: names.into_iter().map(|s| s.name).collect(),
         }
     }
 }
 
+/// A bunch of people who go on outings together. The group's outings can all
+/// be settled up at once.
+#[derive(Serialize, FromRow)]
+pub struct Group {
+    pub group_id: GroupId,
+    pub created_at: DateTime<Utc>,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct GroupNew {
+    pub name: String,
+    // Everybody in these becomes part of each outing created in the group
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct GroupDetails {
+    pub group_id: GroupId,
+    pub created_at: DateTime<Utc>,
+    pub name: String,
+    pub members: Vec<String>,
+    pub outings: Vec<Outing>,
+}
+
+impl GroupDetails {
+    pub fn new(group: Group, members: Vec<Named>, outings: Vec<Outing>) -> Self {
+        Self {
+            group_id: group.group_id,
+            created_at: group.created_at,
+            name: group.name,
+            members: members.into_iter().map(|m| m.name).collect(),
+            outings,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum Schedule {
+    Weekly,
+    Monthly,
+}
+
+/// An expense that comes around on a schedule, like rent. Every time it comes
+/// due it gets added to the group as an outing of its own. See `recurring`.
+#[derive(Serialize, FromRow)]
+pub struct RecurringExpense {
+    pub recurring_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub group_id: GroupId,
+    pub person_name: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: Decimal,
+    pub description: Option<String>,
+    pub schedule: Schedule,
+    pub starts_on: NaiveDate,
+}
+
+#[derive(Deserialize)]
+pub struct RecurringExpenseNew {
+    pub person_name: String,
+    pub amount: Decimal,
+    pub description: Option<String>,
+    pub schedule: Schedule,
+    // Defaults to today
+    pub starts_on: Option<NaiveDate>,
+}
+
+/// The transfers that settle up every one of a group's unsettled outings at
+/// once.
+#[derive(Serialize)]
+pub struct GroupResults {
+    pub outings: Vec<OutingId>,
+    pub results: Vec<OutingResult>,
+}
+
 #[derive(Serialize, Deserialize, FromRow)]
 pub struct OutingPerson {
     pub outing_id: OutingId,
     pub name: String,
 }
 
-#[derive(Serialize, FromRow)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum ExpenseKind {
+    /// Something bought for the whole outing, split evenly between everyone
+    #[default]
+    Purchase,
+    /// Money the payer got back on everyone's behalf, like a returned item or a
+    /// group discount. Credited back to everyone evenly.
+    Refund,
+    /// Money the payer gave straight to the recipient, which only concerns
+    /// the two of them
+    Transfer,
+}
+
+impl Display for ExpenseKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ExpenseKind::Purchase => "purchase",
+            ExpenseKind::Refund => "refund",
+            ExpenseKind::Transfer => "transfer",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, FromRow, Debug)]
 pub struct Expense {
     pub expense_id: i32,
     pub created_at: DateTime<Utc>,
+    // When the money was spent, as opposed to when the expense was added
+    pub incurred_at: NaiveDate,
     pub outing_id: OutingId,
     pub person_name: String,
-    #[serde(with = "rust_decimal::serde::float")]
+    #[serde(serialize_with = "serialize_amount")]
     pub amount: Decimal,
     pub description: Option<String>,
+    pub category: Option<String>,
+    pub tags: Vec<String>,
+    pub kind: ExpenseKind,
+    // Who a transfer went to. Only transfers have one.
+    pub recipient: Option<String>,
+}
+
+impl Expense {
+    /// How much this expense adds to what the whole outing spent, which is
+    /// negative for refunds. Transfers don't add anything.
+    pub fn cost(&self) -> Decimal {
+        match self.kind {
+            ExpenseKind::Purchase => self.amount,
+            ExpenseKind::Refund => -self.amount,
+            ExpenseKind::Transfer => Decimal::ZERO,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -154,24 +367,555 @@ pub struct ExpenseNew {
     pub person_name: String,
     pub amount: Decimal,
     pub description: Option<String>,
+    // Anything goes, though the app suggests food, lodging and transport.
+    // See `normalize_category`.
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Defaults to the day the expense is added
+    pub incurred_at: Option<NaiveDate>,
+    #[serde(default)]
+    pub kind: ExpenseKind,
+    pub recipient: Option<String>,
+}
+
+/// Money handed straight from one person to another, e.g. cash mid-trip. It
+/// only changes where those two people stand. Stored as an expense of kind
+/// `ExpenseKind::Transfer`, with the same ID.
+#[derive(Clone, Serialize, FromRow, Debug)]
+pub struct Transfer {
+    pub transfer_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub incurred_at: NaiveDate,
+    pub outing_id: OutingId,
+    pub from: String,
+    pub to: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: Decimal,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TransferNew {
+    pub from: String,
+    pub to: String,
+    pub amount: Decimal,
+    pub description: Option<String>,
+    // Defaults to the day the transfer is added
+    pub incurred_at: Option<NaiveDate>,
+}
+
+/// Something one person ordered on an itemized bill
+#[derive(Clone, Serialize, Deserialize, FromRow, PartialEq, Debug)]
+pub struct BillItem {
+    pub person_name: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: Decimal,
+    pub description: Option<String>,
+}
+
+/// Tax, tip, a service fee or anything else on an itemized bill that gets
+/// shared out in proportion to what everybody ordered
+#[derive(Clone, Serialize, Deserialize, FromRow, PartialEq, Debug)]
+pub struct BillExtra {
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: Decimal,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ItemizedExpenseNew {
+    pub outing_id: OutingId,
+    // Who paid the bill
+    pub person_name: String,
+    pub description: Option<String>,
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub incurred_at: Option<NaiveDate>,
+    pub items: Vec<BillItem>,
+    #[serde(default)]
+    pub extras: Vec<BillExtra>,
+}
+
+/// What one person owes for an itemized bill. Expenses with shares are split
+/// by them in settlement, rather than evenly between everybody.
+#[derive(Clone, Serialize, FromRow, PartialEq, Debug)]
+pub struct ExpenseShare {
+    #[serde(skip)]
+    pub expense_id: i32,
+    pub person_name: String,
+    // What they ordered
+    #[serde(serialize_with = "serialize_amount")]
+    pub subtotal: Decimal,
+    // Their part of the bill's extras
+    #[serde(serialize_with = "serialize_amount")]
+    pub extras: Decimal,
+    #[serde(serialize_with = "serialize_amount")]
+    pub share: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct ItemizedExpense {
+    #[serde(flatten)]
+    pub expense: Expense,
+    pub items: Vec<BillItem>,
+    pub extras: Vec<BillExtra>,
+    pub shares: Vec<ExpenseShare>,
+}
+
+/// Checks an itemized bill's lines, returning its total
+pub fn check_bill(items: &[BillItem], extras: &[BillExtra]) -> Result<Decimal, String> {
+    if items.is_empty() {
+        return Err("Itemized bills need at least one item".to_string());
+    }
+    for item in items {
+        if item.person_name.trim().is_empty() {
+            return Err("person_name must not be blank".to_string());
+        }
+    }
+    for amount in items
+        .iter()
+        .map(|i| &i.amount)
+        .chain(extras.iter().map(|e| &e.amount))
+    {
+        check_amount(amount)?;
+        if amount.is_sign_negative() {
+            return Err("Items and extras must not have negative amounts".to_string());
+        }
+    }
+
+    let subtotal: Decimal = items.iter().map(|i| i.amount).sum();
+    if subtotal.is_zero() {
+        return Err(
+            "Extras are shared in proportion to the items, so they can't all be free".to_string(),
+        );
+    }
+    let total = subtotal + extras.iter().map(|e| e.amount).sum::<Decimal>();
+    check_amount(&total)?;
+
+    Ok(total)
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Receipt {
+    pub expense_id: i32,
+    pub uploaded_at: DateTime<Utc>,
+    // Where the receipt store keeps it, which clients have no use for
+    #[serde(skip)]
+    pub object_key: String,
+    pub content_type: String,
+    pub size: i32,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ExpenseFilters {
+    // Only expenses incurred on or after this date
+    pub from: Option<NaiveDate>,
+    // Only expenses incurred on or before this date
+    pub to: Option<NaiveDate>,
+    // Only expenses paid by this person
+    pub payer: Option<String>,
+    // Only expenses of at least this much
+    pub min_amount: Option<Decimal>,
+    // Only expenses of at most this much
+    pub max_amount: Option<Decimal>,
+    // At most this many expenses, or all of them if not given. See
+    // `MAX_PAGE_SIZE`.
+    pub limit: Option<i64>,
+    // How many expenses to skip, for fetching later pages
+    #[serde(default)]
+    pub offset: i64,
+    // The listing shows transfers separately from shared expenses, but exports
+    // want everything
+    #[serde(skip)]
+    pub include_transfers: bool,
+}
+
+impl ExpenseFilters {
+    /// Every expense in the outing, transfers included
+    pub fn everything() -> Self {
+        ExpenseFilters {
+            include_transfers: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// The most expenses a single page of a listing can have
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Categories are compared case-insensitively, so they're stored lowercase and
+/// trimmed. Blank categories are as good as none.
+pub fn normalize_category(category: Option<String>) -> Option<String> {
+    category
+        .map(|c| c.trim().to_lowercase())
+        .filter(|c| !c.is_empty())
+}
+
+/// Tags are normalized the same way as categories, without any repeats.
+pub fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags.into_iter().filter_map(|t| normalize_category(Some(t))) {
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+/// Checks that an expense's kind makes sense with its amount and recipient.
+/// Negative purchases are how refunds used to be entered, so they're turned
+/// into refunds, which is why this returns the kind and amount to store.
+pub fn check_kind(
+    kind: ExpenseKind,
+    amount: Decimal,
+    person_name: &str,
+    recipient: Option<&str>,
+) -> Result<(ExpenseKind, Decimal), &'static str> {
+    let (kind, amount) = match kind {
+        ExpenseKind::Purchase if amount.is_sign_negative() => (ExpenseKind::Refund, -amount),
+        _ => (kind, amount),
+    };
+
+    if amount.is_sign_negative() {
+        return Err("Refunds and transfers must not have negative amounts");
+    }
+    match (kind, recipient) {
+        (ExpenseKind::Transfer, None) => Err("Transfers must have a recipient"),
+        (ExpenseKind::Transfer, Some(recipient)) if recipient == person_name => {
+            Err("People can't transfer money to themselves")
+        }
+        (ExpenseKind::Transfer, Some(_)) => Ok((kind, amount)),
+        (_, Some(_)) => Err("Only transfers can have a recipient"),
+        (_, None) => Ok((kind, amount)),
+    }
+}
+
+/// The largest magnitude our `NUMERIC(9,4)` amount columns can hold
+pub const MAX_AMOUNT: Decimal = Decimal::from_parts(999_999_999, 0, 0, false, 4);
+
+pub fn check_amount(amount: &Decimal) -> Result<(), &'static str> {
+    if amount.normalize().scale() > 4 {
+        Err("Amounts must not have more than 4 decimal places")
+    } else if amount.abs() > MAX_AMOUNT {
+        Err("Amounts must be less than 100,000")
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExpenseImportRow {
+    pub person_name: String,
+    pub amount: Decimal,
+    pub description: Option<String>,
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub incurred_at: Option<NaiveDate>,
+    #[serde(default)]
+    pub kind: ExpenseKind,
+    pub recipient: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct ImportRowError {
+    pub row: usize,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub people_added: Vec<String>,
+    pub expenses: Vec<Expense>,
+    pub errors: Vec<ImportRowError>,
+}
+
+fn default_currency() -> String {
+    "USD".to_string()
+}
+
+#[derive(Deserialize)]
+pub struct SplitwiseParams {
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    // Whether the outing's settlements have been paid and should be exported
+    // as Splitwise payments
+    #[serde(default)]
+    pub settled: bool,
+}
+
+/// Account names may use `{person}` and `{outing}` placeholders, which are
+/// filled in with the person's name and the outing's name.
+#[derive(Deserialize)]
+pub struct LedgerParams {
+    #[serde(default = "LedgerParams::default_asset_account")]
+    pub asset_account: String,
+    #[serde(default = "LedgerParams::default_receivable_account")]
+    pub receivable_account: String,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+}
+
+impl LedgerParams {
+    fn default_asset_account() -> String {
+        "Assets:Birdie:{person}".to_string()
+    }
+
+    fn default_receivable_account() -> String {
+        "Receivables:Birdie:{outing}:{person}".to_string()
+    }
 }
 
 #[derive(Serialize, FromRow)]
 pub struct Balance {
-    #[serde(with = "rust_decimal::serde::float")]
+    #[serde(serialize_with = "serialize_amount")]
     pub total: Decimal,
 }
 
+/// What somebody paid in an outing, what they handed over in transfers less
+/// what they received, and their share of its itemized bills, which they
+/// cover instead of an even split of those bills.
+#[derive(FromRow)]
+pub struct PersonExpenses {
+    pub name: String,
+    pub amount_paid: Decimal,
+    pub transferred: Decimal,
+    pub itemized_share: Decimal,
+    pub expense_count: i64,
+}
+
+/// Where somebody stands in an outing. `share` and `net` are rounded the same
+/// way as the outing's settlement, so a positive `net` is exactly what the
+/// person gets back and a negative one is exactly what they pay. `paid` and
+/// `expense_count` leave out transfers, which are in `transferred` instead.
+#[derive(Serialize)]
+pub struct PersonBalance {
+    pub name: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub paid: Decimal,
+    #[serde(serialize_with = "serialize_amount")]
+    pub transferred: Decimal,
+    #[serde(serialize_with = "serialize_amount")]
+    pub share: Decimal,
+    #[serde(serialize_with = "serialize_amount")]
+    pub net: Decimal,
+    pub expense_count: i64,
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+}
+
+/// An expense matching a search. `headline` is the expense's description as
+/// escaped HTML with the matching words wrapped in `<mark>` tags, if it has a
+/// description.
+#[derive(Serialize, FromRow)]
+pub struct ExpenseMatch {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub expense: Expense,
+    pub headline: Option<String>,
+    pub matched_tags: Vec<String>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct CategoryTotal {
+    // None for expenses without a category
+    pub category: Option<String>,
+    #[serde(serialize_with = "serialize_amount")]
+    pub total: Decimal,
+    pub expense_count: i64,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct PersonCategoryTotal {
+    pub person_name: String,
+    pub category: Option<String>,
+    #[serde(serialize_with = "serialize_amount")]
+    pub total: Decimal,
+    pub expense_count: i64,
+}
+
+/// Where an outing's money went, for looking back on a trip.
+#[derive(Serialize)]
+pub struct OutingSummary {
+    #[serde(serialize_with = "serialize_amount")]
+    pub total: Decimal,
+    pub categories: Vec<CategoryTotal>,
+    pub people: Vec<PersonCategoryTotal>,
+}
+
+#[derive(FromRow, Debug)]
+pub struct PersonPaid {
+    pub name: String,
+    pub amount_paid: Decimal,
+}
+
 #[derive(FromRow, Debug)]
 pub struct PersonDiff {
     pub name: String,
     pub diff_from_avg: Decimal,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct OutingResult {
     pub from: String,
     pub to: String,
-    #[serde(with = "rust_decimal::serde::float")]
+    #[serde(serialize_with = "serialize_amount")]
     pub amount: Decimal,
 }
+
+/// Expenses to add to or leave out of an outing when previewing how it would
+/// settle up. Nothing in a preview is ever saved.
+#[derive(Deserialize, Default)]
+pub struct SettlementPreview {
+    #[serde(default)]
+    pub expenses: Vec<ExpenseNew>,
+    // IDs of saved expenses to pretend don't exist
+    #[serde(default)]
+    pub exclude: Vec<i32>,
+}
+
+#[derive(Serialize)]
+pub struct OutingResults {
+    pub rounding: RoundingPolicy,
+    pub results: Vec<OutingResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Explanation>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum ConstraintKind {
+    /// The payer can't send the payee money at all
+    Forbidden,
+    /// The payer would rather pay the payee than anybody else
+    Preferred,
+}
+
+/// Limits on who can pay whom when an outing settles up, e.g. for people who
+/// use different payment apps.
+#[derive(Serialize, Deserialize, FromRow, PartialEq, Debug)]
+pub struct SettlementConstraint {
+    pub payer: String,
+    pub payee: String,
+    pub kind: ConstraintKind,
+}
+
+/// How an outing's transfers get worked out once everybody's position is
+/// known. See `settle`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementStrategy {
+    /// As few transfers as we can manage
+    #[default]
+    Greedy,
+    /// Everybody settles through one treasurer
+    Hub,
+}
+
+#[derive(Deserialize, Default)]
+pub struct FinishParams {
+    #[serde(default)]
+    pub explain: bool,
+    #[serde(default)]
+    pub strategy: SettlementStrategy,
+    // Who everybody settles through under the hub strategy
+    pub treasurer: Option<String>,
+}
+
+/// How an outing's settlement was worked out: where everybody started, then
+/// each transfer in the order the algorithm picked it.
+#[derive(Serialize)]
+pub struct Explanation {
+    pub people: Vec<PersonBalance>,
+    pub steps: Vec<SettlementStep>,
+}
+
+#[derive(Serialize)]
+pub struct SettlementStep {
+    // Everybody not yet square, most indebted first. Negative means owed.
+    pub before: Vec<Position>,
+    #[serde(flatten)]
+    pub transfer: OutingResult,
+}
+
+#[derive(Serialize)]
+pub struct Position {
+    pub name: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub owes: Decimal,
+}
+
+/// Bump this whenever `OutingArchive` changes in a way older readers can't
+/// handle, and teach the import route how to read the old versions.
+///
+/// Version 2 added expense kinds, transfer recipients and itemized bills.
+/// Version 1 archives only have purchases, which is what those fields default
+/// to.
+pub const ARCHIVE_VERSION: u32 = 2;
+
+/// A portable, self-contained copy of an outing which can be exported from one
+/// Birdie instance and imported into another. Outing IDs are deliberately left
+/// out since the importing instance assigns its own; everything else, including
+/// timestamps, comes along as-is. Amounts are written as decimal strings.
+///
+/// `settlements` are included for the benefit of people reading the archive,
+/// but are recomputed from the expenses on import rather than trusted.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct OutingArchive {
+    pub version: u32,
+    pub outing: ArchivedOuting,
+    pub people: Vec<String>,
+    pub expenses: Vec<ArchivedExpense>,
+    #[serde(default)]
+    pub settlements: Vec<OutingResult>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ArchivedOuting {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub rounding: RoundingPolicy,
+}
+
+#[derive(Serialize, Deserialize, FromRow, PartialEq, Debug)]
+pub struct ArchivedExpense {
+    pub created_at: DateTime<Utc>,
+    // Older archives don't have this, in which case it defaults to the day
+    // the expense was added
+    #[serde(default)]
+    pub incurred_at: Option<NaiveDate>,
+    pub person_name: String,
+    pub amount: Decimal,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub kind: ExpenseKind,
+    #[serde(default)]
+    pub recipient: Option<String>,
+    // Only used to look up an itemized bill's lines when exporting
+    #[serde(skip)]
+    pub expense_id: i32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[sqlx(skip)]
+    pub items: Vec<BillItem>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[sqlx(skip)]
+    pub extras: Vec<BillExtra>,
+}
@@ -0,0 +1,117 @@
+/*
+ * Copyright © 2023 Jonathan Ming
+ *
+ * This file is part of Birdie.
+ *
+ * For information about warranty and licensing, see the disclaimer in
+ * src/lib.rs as well as the LICENSE file.
+ */
+use std::{io, path::PathBuf};
+
+use aws_sdk_s3::{error::SdkError, primitives::ByteStream, Client};
+use shuttle_secrets::SecretStore;
+
+use crate::{models::OutingId, s3};
+
+/// The largest receipt we'll accept, which is plenty for a phone photo
+pub const MAX_RECEIPT_SIZE: usize = 10 * 1024 * 1024;
+
+/// The kinds of file we accept as receipts, and the bytes each kind starts
+/// with. WebP files start with "RIFF", then the size, then "WEBP", which is
+/// checked separately.
+const CONTENT_TYPES: [(&str, &[u8]); 4] = [
+    ("image/jpeg", b"\xFF\xD8\xFF"),
+    ("image/png", b"\x89PNG\r\n\x1A\n"),
+    ("image/webp", b"RIFF"),
+    ("application/pdf", b"%PDF-"),
+];
+
+pub fn is_supported(content_type: &str) -> bool {
+    CONTENT_TYPES.iter().any(|(ct, _)| *ct == content_type)
+}
+
+/// Whether `bytes` really are a file of the given content type, so that we
+/// never serve something other than what the uploader said it was.
+pub fn looks_like(content_type: &str, bytes: &[u8]) -> bool {
+    let Some((_, magic)) = CONTENT_TYPES.iter().find(|(ct, _)| *ct == content_type) else {
+        return false;
+    };
+
+    bytes.starts_with(magic)
+        && (content_type != "image/webp" || bytes.get(8..12) == Some(b"WEBP".as_slice()))
+}
+
+/// Where receipt images are kept. Objects are stored under a prefix per
+/// outing, see `ReceiptStore::key`.
+#[derive(Clone)]
+pub enum ReceiptStore {
+    S3 { client: Client, bucket: String },
+    // For tests and self-hosting
+    Local { root: PathBuf },
+}
+
+impl ReceiptStore {
+    pub async fn s3(
+        secret_store: &SecretStore,
+        bucket: impl Into<String>,
+    ) -> Result<Self, shuttle_runtime::Error> {
+        Ok(ReceiptStore::S3 {
+            client: s3::get_client(secret_store).await?,
+            bucket: bucket.into(),
+        })
+    }
+
+    pub fn local(root: impl Into<PathBuf>) -> Self {
+        ReceiptStore::Local { root: root.into() }
+    }
+
+    pub fn key(outing_id: OutingId, expense_id: i32) -> String {
+        format!("receipts/{}/{}", String::from(outing_id), expense_id)
+    }
+
+    /// Stores a receipt, replacing whatever was already at `key`
+    pub async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> io::Result<()> {
+        match self {
+            ReceiptStore::S3 { client, bucket } => {
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .content_type(content_type)
+                    .body(ByteStream::from(bytes))
+                    .send()
+                    .await
+                    .map_err(io::Error::other)?;
+            }
+            ReceiptStore::Local { root } => {
+                let path = root.join(key);
+                if let Some(dir) = path.parent() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                tokio::fs::write(path, bytes).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches a receipt, or `None` if there's nothing at `key`
+    pub async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            ReceiptStore::S3 { client, bucket } => {
+                let result = client.get_object().bucket(bucket).key(key).send().await;
+                let output = match result {
+                    Ok(output) => output,
+                    Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => return Ok(None),
+                    Err(e) => return Err(io::Error::other(e)),
+                };
+                let body = output.body.collect().await.map_err(io::Error::other)?;
+                Ok(Some(body.into_bytes().to_vec()))
+            }
+            ReceiptStore::Local { root } => match tokio::fs::read(root.join(key)).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
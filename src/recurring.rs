@@ -0,0 +1,179 @@
+/*
+ * Copyright © 2023 Jonathan Ming
+ *
+ * This file is part of Birdie.
+ *
+ * For information about warranty and licensing, see the disclaimer in
+ * src/lib.rs as well as the LICENSE file.
+ */
+use std::time::Duration;
+
+use chrono::{Days, Months, NaiveDate, Utc};
+use sqlx::PgPool;
+use tracing::{error, info};
+
+use crate::models::{OutingId, RecurringExpense, Schedule};
+
+/// How often the background task checks for recurring expenses that have come
+/// due. Occurrences are by the day, so this only needs to be well under that.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The date of a recurring expense's `n`th occurrence, counting from zero.
+/// Monthly expenses that start late in the month fall on the last day of any
+/// shorter months, then go back to their usual day afterwards.
+fn occurrence(recurring: &RecurringExpense, n: u32) -> Option<NaiveDate> {
+    match recurring.schedule {
+        Schedule::Weekly => recurring
+            .starts_on
+            .checked_add_days(Days::new(7 * u64::from(n))),
+        Schedule::Monthly => recurring.starts_on.checked_add_months(Months::new(n)),
+    }
+}
+
+/// Adds every occurrence of every recurring expense which has come due by
+/// `today` and hasn't been added yet. Each occurrence becomes an outing in the
+/// recurring expense's group, with all the group's members in it and the
+/// expense already paid.
+///
+/// This is safe to run as often as you like, including from several places at
+/// once: each occurrence is claimed in `recurring_occurrences` in the same
+/// transaction that creates its outing, so it can only ever be added once.
+/// A recurring expense that can't be added is logged and skipped, so it
+/// doesn't hold up any of the others. Returns the outings that were created.
+pub async fn materialize(pool: &PgPool, today: NaiveDate) -> Result<Vec<OutingId>, sqlx::Error> {
+    let templates: Vec<RecurringExpense> = sqlx::query_as(
+        "SELECT * FROM recurring_expenses WHERE starts_on <= $1 ORDER BY recurring_id",
+    )
+    .bind(today)
+    .fetch_all(pool)
+    .await?;
+
+    let mut created = vec![];
+
+    for recurring in templates {
+        if let Err(e) = materialize_one(pool, &recurring, today, &mut created).await {
+            error!(
+                "Failed to add recurring expense {}: {}",
+                recurring.recurring_id, e
+            );
+        }
+    }
+
+    Ok(created)
+}
+
+/// Adds the occurrences of one recurring expense that have come due, in order,
+/// stopping at the first one that can't be added.
+async fn materialize_one(
+    pool: &PgPool,
+    recurring: &RecurringExpense,
+    today: NaiveDate,
+    created: &mut Vec<OutingId>,
+) -> Result<(), sqlx::Error> {
+    let last: (Option<NaiveDate>,) =
+        sqlx::query_as("SELECT MAX(occurs_on) FROM recurring_occurrences WHERE recurring_id = $1")
+            .bind(recurring.recurring_id)
+            .fetch_one(pool)
+            .await?;
+
+    let due = (0u32..)
+        .map_while(|n| occurrence(recurring, n))
+        .take_while(|date| *date <= today)
+        .filter(|date| last.0.is_none_or(|last| *date > last));
+
+    for occurs_on in due {
+        if let Some(outing_id) = add_occurrence(pool, recurring, occurs_on).await? {
+            created.push(outing_id);
+        }
+    }
+
+    Ok(())
+}
+
+async fn add_occurrence(
+    pool: &PgPool,
+    recurring: &RecurringExpense,
+    occurs_on: NaiveDate,
+) -> Result<Option<OutingId>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let claimed = sqlx::query(
+        "INSERT INTO recurring_occurrences(recurring_id, occurs_on) \
+         VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(recurring.recurring_id)
+    .bind(occurs_on)
+    .execute(&mut *tx)
+    .await?;
+
+    // Somebody else already added this one
+    if claimed.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let name = format!(
+        "{} ({})",
+        recurring
+            .description
+            .as_deref()
+            .unwrap_or("Recurring expense"),
+        occurs_on
+    );
+    let (outing_id,): (OutingId,) =
+        sqlx::query_as("INSERT INTO outings(name, group_id) VALUES ($1, $2) RETURNING outing_id")
+            .bind(name)
+            .bind(&recurring.group_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+    sqlx::query(
+        "INSERT INTO outing_people(outing_id, name) \
+         SELECT $1, name FROM group_members WHERE group_id = $2",
+    )
+    .bind(&outing_id)
+    .bind(&recurring.group_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO expenses(outing_id, person_name, amount, description, incurred_at) \
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(&outing_id)
+    .bind(&recurring.person_name)
+    .bind(recurring.amount)
+    .bind(&recurring.description)
+    .bind(occurs_on)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        "UPDATE recurring_occurrences SET outing_id = $3 \
+         WHERE recurring_id = $1 AND occurs_on = $2",
+    )
+    .bind(recurring.recurring_id)
+    .bind(occurs_on)
+    .bind(&outing_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(outing_id))
+}
+
+/// Runs forever, adding recurring expenses as they come due. Meant to be
+/// spawned once at startup.
+pub async fn run(pool: PgPool) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        match materialize(&pool, Utc::now().date_naive()).await {
+            Ok(created) if !created.is_empty() => {
+                info!("Added {} recurring expense outing(s)", created.len())
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to add recurring expenses: {}", e),
+        }
+    }
+}
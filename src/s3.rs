@@ -12,7 +12,7 @@ use aws_sdk_s3::{config::Credentials, operation::get_object::GetObjectOutput, Cl
 use shuttle_runtime::CustomError;
 use shuttle_secrets::SecretStore;
 
-async fn get_client(secret_store: &SecretStore) -> Result<Client, shuttle_runtime::Error> {
+pub async fn get_client(secret_store: &SecretStore) -> Result<Client, shuttle_runtime::Error> {
     let aws_ak = secret_store
         .get("AWS_ACCESS_KEY_ID")
         .ok_or_else(|| CustomError::msg("Could not find AWS access secrets"))?;
@@ -0,0 +1,570 @@
+/*
+ * Copyright © 2023 Jonathan Ming
+ *
+ * This file is part of Birdie.
+ *
+ * For information about warranty and licensing, see the disclaimer in
+ * src/lib.rs as well as the LICENSE file.
+ */
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+
+use sqlx::types::Decimal;
+
+use crate::models::{
+    BillExtra, BillItem, ConstraintKind, ExpenseShare, OutingResult, PersonBalance, PersonDiff,
+    PersonExpenses, PersonPaid, Position, RoundingPolicy, SettlementConstraint, SettlementStep,
+};
+
+/// Amounts are stored with 4 decimal places, so everything below is done in
+/// integer ten-thousandths to keep it exact.
+const SCALE: u32 = 4;
+
+fn to_units(amount: Decimal) -> i128 {
+    scaled(amount).mantissa()
+}
+
+fn scaled(mut amount: Decimal) -> Decimal {
+    amount.rescale(SCALE);
+    amount
+}
+
+/// The smallest amount anybody gets asked to pay under a rounding policy.
+fn increment(policy: RoundingPolicy) -> Decimal {
+    match policy {
+        RoundingPolicy::Cent | RoundingPolicy::Bankers | RoundingPolicy::PayersUp => {
+            Decimal::new(1, 2)
+        }
+        RoundingPolicy::Nickel => Decimal::new(5, 2),
+        RoundingPolicy::Whole => Decimal::ONE,
+    }
+}
+
+/// Works out how far each person is from paying their fair share, rounded to
+/// a multiple of the rounding policy's increment. A positive diff means the
+/// person owes the group, and negative means the group owes them.
+///
+/// Rounding everybody independently could leave the diffs adding up to a few
+/// cents more or less than zero, so after rounding everyone per the policy we
+/// settle up the difference one increment at a time, starting with the people
+/// the rounding was least fair to (ties going to whoever comes first
+/// alphabetically). This is the largest remainder method when rounding down.
+/// That way the diffs always sum to exactly zero.
+pub fn positions(paid: &[PersonPaid], policy: RoundingPolicy) -> Vec<PersonDiff> {
+    if paid.is_empty() {
+        return vec![];
+    }
+
+    let n = paid.len() as i128;
+    let paid_units: Vec<i128> = paid.iter().map(|p| to_units(p.amount_paid)).collect();
+    let total: i128 = paid_units.iter().sum();
+
+    // Each diff is (total / n - paid) units, or (total - n * paid) / (n * inc)
+    // in increments, which we can round exactly using integer division.
+    let denominator = n * to_units(increment(policy));
+    let numerators: Vec<i128> = paid_units.iter().map(|p| total - n * p).collect();
+
+    let mut rounded: Vec<i128> = numerators
+        .iter()
+        .map(|&numerator| {
+            let floor = numerator.div_euclid(denominator);
+            let remainder = numerator.rem_euclid(denominator);
+            match policy {
+                RoundingPolicy::Cent | RoundingPolicy::Nickel | RoundingPolicy::Whole => floor,
+                RoundingPolicy::Bankers => {
+                    if 2 * remainder > denominator
+                        || (2 * remainder == denominator && floor.rem_euclid(2) == 1)
+                    {
+                        floor + 1
+                    } else {
+                        floor
+                    }
+                }
+                RoundingPolicy::PayersUp => {
+                    if remainder > 0 {
+                        floor + 1
+                    } else {
+                        floor
+                    }
+                }
+            }
+        })
+        .collect();
+
+    // How much each person lost to rounding, still in the same units as the
+    // numerators. Negative when they were rounded up.
+    let lost: Vec<i128> = numerators
+        .iter()
+        .zip(&rounded)
+        .map(|(numerator, r)| numerator - r * denominator)
+        .collect();
+
+    // Each person's rounding is off by less than one increment, so this is
+    // always less than the number of people
+    let leftover = -rounded.iter().sum::<i128>();
+
+    // When payers round up, whatever that collects goes to the people who are
+    // owed rather than back to the payers
+    let mut candidates: Vec<usize> = (0..paid.len())
+        .filter(|&i| policy != RoundingPolicy::PayersUp || numerators[i] < 0)
+        .collect();
+    candidates.sort_by(|&a, &b| {
+        let by_loss = if leftover > 0 {
+            lost[b].cmp(&lost[a])
+        } else {
+            lost[a].cmp(&lost[b])
+        };
+        by_loss.then_with(|| paid[a].name.cmp(&paid[b].name))
+    });
+    for &i in candidates
+        .iter()
+        .cycle()
+        .take(leftover.unsigned_abs() as usize)
+    {
+        rounded[i] += leftover.signum();
+    }
+
+    paid.iter()
+        .zip(rounded)
+        .map(|(p, increments)| PersonDiff {
+            name: p.name.clone(),
+            diff_from_avg: Decimal::from_i128_with_scale(increments, 0) * increment(policy),
+        })
+        .collect()
+}
+
+/// Adds up everybody's diffs from several outings, matching people up by name.
+/// Each outing's diffs sum to zero, so the totals do too.
+pub fn net(outings: Vec<Vec<PersonDiff>>) -> Vec<PersonDiff> {
+    let mut totals: BTreeMap<String, Decimal> = BTreeMap::new();
+    for diff in outings.into_iter().flatten() {
+        *totals.entry(diff.name).or_default() += diff.diff_from_avg;
+    }
+
+    totals
+        .into_iter()
+        .map(|(name, diff_from_avg)| PersonDiff {
+            name,
+            diff_from_avg,
+        })
+        .collect()
+}
+
+/// Splits a number of units between people in proportion to their weights,
+/// which must not all be zero. Each person gets their exact portion rounded
+/// down, then whatever is left over goes a unit at a time to whoever lost the
+/// most in rounding, ties going to whoever comes first. The parts always add
+/// back up to exactly `units`.
+pub fn allocate_units(units: i128, weights: &[i128]) -> Vec<i128> {
+    let total: i128 = weights.iter().sum();
+    let exact: Vec<i128> = weights.iter().map(|w| units * w).collect();
+    let mut parts: Vec<i128> = exact.iter().map(|e| e.div_euclid(total)).collect();
+
+    // Each part is off by less than one unit, so this is less than the number
+    // of people
+    let leftover = units - parts.iter().sum::<i128>();
+    let mut order: Vec<usize> = (0..parts.len()).collect();
+    order.sort_by_key(|&i| Reverse(exact[i].rem_euclid(total)));
+    for &i in order.iter().take(leftover as usize) {
+        parts[i] += 1;
+    }
+
+    parts
+}
+
+/// Works out everybody's share of an itemized bill: what they ordered, plus the
+/// bill's extras in proportion to that. The bill must have passed
+/// `check_bill`. Shares are exact to the ten-thousandth and add up to exactly
+/// the bill's total, and people come out in alphabetical order.
+pub fn itemize(expense_id: i32, items: &[BillItem], extras: &[BillExtra]) -> Vec<ExpenseShare> {
+    let mut subtotals: BTreeMap<&str, Decimal> = BTreeMap::new();
+    for item in items {
+        *subtotals.entry(&item.person_name).or_default() += item.amount;
+    }
+
+    let extras_total: Decimal = extras.iter().map(|e| e.amount).sum();
+    let weights: Vec<i128> = subtotals.values().map(|s| to_units(*s)).collect();
+    let allocated = allocate_units(to_units(extras_total), &weights);
+
+    subtotals
+        .into_iter()
+        .zip(allocated)
+        .map(|((name, subtotal), extras)| {
+            let extras = Decimal::from_i128_with_scale(extras, SCALE);
+            ExpenseShare {
+                expense_id,
+                person_name: name.to_string(),
+                subtotal: scaled(subtotal),
+                extras,
+                share: scaled(subtotal + extras),
+            }
+        })
+        .collect()
+}
+
+/// Puts together what each person paid with their rounded diff from
+/// `positions`, which must be in the same order.
+pub fn balances(people: Vec<PersonExpenses>, diffs: &[PersonDiff]) -> Vec<PersonBalance> {
+    people
+        .into_iter()
+        .zip(diffs)
+        .map(|(p, diff)| PersonBalance {
+            name: p.name,
+            paid: scaled(p.amount_paid),
+            transferred: scaled(p.transferred),
+            share: scaled(p.amount_paid + p.transferred + diff.diff_from_avg),
+            net: scaled(-diff.diff_from_avg),
+            expense_count: p.expense_count,
+        })
+        .collect()
+}
+
+/// Turns everybody's diffs into a list of transfers. Each round, the most
+/// indebted person pays off their whole debt to the person who is owed the
+/// most, which may leave that person owing the difference themselves. Each
+/// step also records where everybody stood before its transfer, like the
+/// worked tables in `math.org`.
+///
+/// The diffs must sum to exactly zero, which `positions` guarantees.
+pub fn greedy_steps(diffs: Vec<PersonDiff>) -> Vec<SettlementStep> {
+    // People who are already square don't need to do anything
+    let mut people_debts: VecDeque<PersonDiff> = diffs
+        .into_iter()
+        .filter(|pd| !pd.diff_from_avg.is_zero())
+        .collect();
+
+    let mut steps = Vec::with_capacity(people_debts.len());
+
+    loop {
+        // Sorts in ascending order, so the person with highest debt to the
+        // group comes at the back
+        people_debts
+            .make_contiguous()
+            .sort_by_key(|pd| pd.diff_from_avg);
+
+        // Listed most indebted first, the same way the tables are
+        let before = snapshot(people_debts.iter().rev());
+
+        // Since the diffs sum to zero and nobody left is square, there's
+        // always somebody owed for as long as there's somebody indebted
+        let Some(most_indebted) = people_debts.pop_back() else {
+            break;
+        };
+        let Some(most_owed) = people_debts.front_mut() else {
+            break;
+        };
+
+        steps.push(SettlementStep {
+            before,
+            transfer: OutingResult {
+                from: most_indebted.name,
+                to: most_owed.name.clone(),
+                amount: most_indebted.diff_from_avg,
+            },
+        });
+        // This works because most_owed's diff should be negative, while
+        // most_indebted's diff should be positive.
+        most_owed.diff_from_avg += most_indebted.diff_from_avg;
+        if most_owed.diff_from_avg.is_zero() {
+            people_debts.pop_front();
+        }
+    }
+
+    steps
+}
+
+/// Has everybody settle through the treasurer instead: first everybody who owes
+/// pays the treasurer, most indebted first, and then the treasurer pays out
+/// everybody who is owed, most owed first. This takes more transfers than
+/// `greedy`, but nobody has to pay anyone other than the treasurer.
+///
+/// The treasurer must be one of the people in `diffs`.
+pub fn hub_steps(mut diffs: Vec<PersonDiff>, treasurer: &str) -> Vec<SettlementStep> {
+    diffs.sort_by(|a, b| {
+        b.diff_from_avg
+            .cmp(&a.diff_from_avg)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    let Some(hub) = diffs.iter().position(|pd| pd.name == treasurer) else {
+        return vec![];
+    };
+
+    let mut steps = vec![];
+
+    let payers =
+        (0..diffs.len()).filter(|&i| i != hub && diffs[i].diff_from_avg.is_sign_positive());
+    let payees =
+        (0..diffs.len()).filter(|&i| i != hub && diffs[i].diff_from_avg.is_sign_negative());
+    for i in payers.chain(payees.rev()).collect::<Vec<_>>() {
+        let amount = diffs[i].diff_from_avg;
+        if amount.is_zero() {
+            continue;
+        }
+
+        let mut before = snapshot(diffs.iter().filter(|pd| !pd.diff_from_avg.is_zero()));
+        before.sort_by_key(|p| Reverse(p.owes));
+        let transfer = if amount.is_sign_positive() {
+            OutingResult {
+                from: diffs[i].name.clone(),
+                to: diffs[hub].name.clone(),
+                amount,
+            }
+        } else {
+            OutingResult {
+                from: diffs[hub].name.clone(),
+                to: diffs[i].name.clone(),
+                amount: -amount,
+            }
+        };
+        steps.push(SettlementStep { before, transfer });
+
+        diffs[hub].diff_from_avg += amount;
+        diffs[i].diff_from_avg = Decimal::ZERO;
+    }
+
+    steps
+}
+
+/// Like `greedy_steps`, but nobody pays anyone they're forbidden from paying,
+/// and preferred pairs get used before anything else.
+///
+/// Anybody who owes money but isn't allowed to pay anyone who is owed hands
+/// their whole debt to somebody they can pay instead, preferably somebody who
+/// owes money too, since they'll be making a transfer anyway. Then each round
+/// makes the direct transfer that settles the most, preferring transfers that
+/// square both people at once. Every transfer is only made if everybody can
+/// still settle up afterwards.
+///
+/// Nobody gets a debt relayed to them after relaying their own, otherwise two
+/// people who can only pay each other could pass the same debt back and forth
+/// forever. That way there are at most as many relays as people.
+///
+/// Returns the people who would still owe money when the constraints make
+/// settling up impossible.
+pub fn constrained_steps(
+    diffs: Vec<PersonDiff>,
+    constraints: &[SettlementConstraint],
+) -> Result<Vec<SettlementStep>, Vec<PersonDiff>> {
+    let names: Vec<String> = diffs.iter().map(|pd| pd.name.clone()).collect();
+    let mut owes: Vec<Decimal> = diffs.iter().map(|pd| pd.diff_from_avg).collect();
+    let n = names.len();
+
+    // Nobody ever pays themselves
+    let mut allowed: Vec<Vec<bool>> = (0..n).map(|i| (0..n).map(|j| i != j).collect()).collect();
+    let mut preferred = vec![vec![false; n]; n];
+    for c in constraints {
+        let payer = names.iter().position(|name| *name == c.payer);
+        let payee = names.iter().position(|name| *name == c.payee);
+        if let (Some(payer), Some(payee)) = (payer, payee) {
+            match c.kind {
+                ConstraintKind::Forbidden => allowed[payer][payee] = false,
+                ConstraintKind::Preferred => preferred[payer][payee] = true,
+            }
+        }
+    }
+
+    let flows = max_flow(&owes, &allowed);
+    let stuck: Vec<PersonDiff> = (0..n)
+        .filter(|&i| owes[i] > flows.out_of(i))
+        .map(|i| PersonDiff {
+            name: names[i].clone(),
+            diff_from_avg: owes[i] - flows.out_of(i),
+        })
+        .collect();
+    if !stuck.is_empty() {
+        return Err(stuck);
+    }
+
+    let mut steps = vec![];
+    let mut transfer = |owes: &mut Vec<Decimal>, from: usize, to: usize, amount: Decimal| {
+        let mut before: Vec<Position> = (0..n)
+            .filter(|&i| !owes[i].is_zero())
+            .map(|i| Position {
+                name: names[i].clone(),
+                owes: owes[i],
+            })
+            .collect();
+        before.sort_by_key(|p| Reverse(p.owes));
+        steps.push(SettlementStep {
+            before,
+            transfer: OutingResult {
+                from: names[from].clone(),
+                to: names[to].clone(),
+                amount,
+            },
+        });
+        owes[from] -= amount;
+        owes[to] += amount;
+    };
+
+    let feasible = |owes: &[Decimal]| max_flow(owes, &allowed).total == total_owed(owes);
+    let mut relayed = vec![false; n];
+
+    loop {
+        let mut debtors: Vec<usize> = (0..n).filter(|&i| owes[i] > Decimal::ZERO).collect();
+        debtors.sort_by_key(|&i| (Reverse(owes[i]), &names[i]));
+        let stranded = debtors
+            .iter()
+            .filter(|&&from| !(0..n).any(|to| allowed[from][to] && owes[to] < Decimal::ZERO));
+        let mut relays: Vec<(usize, usize)> = stranded
+            .flat_map(|&from| (0..n).map(move |to| (from, to)))
+            .filter(|&(from, to)| allowed[from][to] && !relayed[to])
+            .collect();
+        relays.sort_by_key(|&(from, to)| {
+            (
+                !preferred[from][to],
+                owes[to] <= Decimal::ZERO,
+                &names[from],
+                &names[to],
+            )
+        });
+        let relay = relays.into_iter().find(|&(from, to)| {
+            let mut after = owes.clone();
+            after[to] += owes[from];
+            after[from] = Decimal::ZERO;
+            feasible(&after)
+        });
+        if let Some((from, to)) = relay {
+            let amount = owes[from];
+            transfer(&mut owes, from, to, amount);
+            relayed[from] = true;
+            continue;
+        }
+
+        let mut pairs: Vec<(usize, usize)> = (0..n)
+            .flat_map(|from| (0..n).map(move |to| (from, to)))
+            .filter(|&(from, to)| {
+                allowed[from][to] && owes[from] > Decimal::ZERO && owes[to] < Decimal::ZERO
+            })
+            .collect();
+        pairs.sort_by_key(|&(from, to)| {
+            (
+                !preferred[from][to],
+                owes[from] != -owes[to],
+                Reverse(owes[from].min(-owes[to])),
+                &names[from],
+                &names[to],
+            )
+        });
+
+        // Paying somebody directly can still leave somebody else with nobody
+        // they're allowed to pay, so only take transfers that keep settling
+        // up possible
+        let next = pairs.into_iter().find(|&(from, to)| {
+            let amount = owes[from].min(-owes[to]);
+            let mut after = owes.clone();
+            after[from] -= amount;
+            after[to] += amount;
+            feasible(&after)
+        });
+        let Some((from, to)) = next else {
+            break;
+        };
+        let amount = owes[from].min(-owes[to]);
+        transfer(&mut owes, from, to, amount);
+    }
+
+    // Anything left over has to be passed along through several people at
+    // once
+    let flows = max_flow(&owes, &allowed);
+    for from in 0..n {
+        for to in 0..n {
+            let amount = flows.between[from][to] - flows.between[to][from];
+            if amount > Decimal::ZERO {
+                transfer(&mut owes, from, to, amount);
+            }
+        }
+    }
+
+    Ok(steps)
+}
+
+fn total_owed(owes: &[Decimal]) -> Decimal {
+    owes.iter().filter(|o| **o > Decimal::ZERO).sum()
+}
+
+struct Flows {
+    total: Decimal,
+    // How much goes from each person to each other person
+    between: Vec<Vec<Decimal>>,
+}
+
+impl Flows {
+    fn out_of(&self, i: usize) -> Decimal {
+        let sent: Decimal = self.between[i].iter().sum();
+        let received: Decimal = self.between.iter().map(|row| row[i]).sum();
+        sent - received
+    }
+}
+
+/// Works out how much money can get from the people who owe to the people who
+/// are owed, passing it along any allowed pairs (Edmonds-Karp). Settling up is
+/// only possible if this is everything that's owed.
+fn max_flow(owes: &[Decimal], allowed: &[Vec<bool>]) -> Flows {
+    let n = owes.len();
+    let (source, sink) = (n, n + 1);
+    // Nobody can ever pass along more than everything that's owed
+    let unlimited = total_owed(owes);
+
+    let mut capacity = vec![vec![Decimal::ZERO; n + 2]; n + 2];
+    for i in 0..n {
+        if owes[i] > Decimal::ZERO {
+            capacity[source][i] = owes[i];
+        } else {
+            capacity[i][sink] = -owes[i];
+        }
+        for j in 0..n {
+            if allowed[i][j] {
+                capacity[i][j] = unlimited;
+            }
+        }
+    }
+
+    let mut flow = vec![vec![Decimal::ZERO; n + 2]; n + 2];
+    let mut total = Decimal::ZERO;
+    loop {
+        let mut prev = vec![None; n + 2];
+        let mut queue = VecDeque::from([source]);
+        while let Some(u) = queue.pop_front() {
+            for v in 0..n + 2 {
+                if v != source && prev[v].is_none() && capacity[u][v] > flow[u][v] {
+                    prev[v] = Some(u);
+                    queue.push_back(v);
+                }
+            }
+        }
+        if prev[sink].is_none() {
+            break;
+        }
+
+        let mut amount = unlimited;
+        let mut v = sink;
+        while let Some(u) = prev[v] {
+            amount = amount.min(capacity[u][v] - flow[u][v]);
+            v = u;
+        }
+        let mut v = sink;
+        while let Some(u) = prev[v] {
+            flow[u][v] += amount;
+            flow[v][u] -= amount;
+            v = u;
+        }
+        total += amount;
+    }
+
+    Flows {
+        total,
+        between: (0..n)
+            .map(|i| (0..n).map(|j| flow[i][j].max(Decimal::ZERO)).collect())
+            .collect(),
+    }
+}
+
+fn snapshot<'a>(diffs: impl Iterator<Item = &'a PersonDiff>) -> Vec<Position> {
+    diffs
+        .map(|pd| Position {
+            name: pd.name.clone(),
+            owes: pd.diff_from_avg,
+        })
+        .collect()
+}
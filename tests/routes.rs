@@ -17,10 +17,14 @@ use axum::{
     response::Response,
     Router,
 };
+use birdie::receipts::ReceiptStore;
 use chrono::DateTime;
+use futures_util::{SinkExt, StreamExt};
 use http_body_util::BodyExt;
 use serde_json::{json, Map, Value};
 use sqlx::{postgres::PgPoolOptions, Executor, PgPool};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
 use tower::ServiceExt; // for `app.oneshot()`
 
 async fn setup_test_db(schema_suffix: &str) -> PgPool {
@@ -63,7 +67,11 @@ async fn cleanup(pool: PgPool, schema_suffix: &str) {
 }
 
 async fn get_app(pool: &PgPool) -> Router {
-    birdie::app(pool.clone(), "./js/build").await.unwrap()
+    // Keep receipts uploaded by the tests out of the repo
+    let receipts = std::env::temp_dir().join(format!("birdie-receipts-{}", std::process::id()));
+    birdie::app(pool.clone(), ReceiptStore::local(receipts), "./js/build")
+        .await
+        .unwrap()
 }
 
 async fn body_bytes(response: Response) -> Bytes {
@@ -87,6 +95,18 @@ fn get_number_key(map: &mut Map<String, Value>, key: &str) -> serde_json::value:
     }
 }
 
+async fn get_json(pool: &PgPool, uri: &str) -> Value {
+    let response = get_app(pool)
+        .await
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    serde_json::from_slice(&body).unwrap()
+}
+
 // Following examples https://github.com/tokio-rs/axum/blob/main/examples/testing
 #[tokio::test]
 async fn ping_pong() {
@@ -166,7 +186,10 @@ async fn outings() {
         .pop()
         .unwrap();
 
-    assert_eq!(body, json!({"name": "foo"}));
+    assert_eq!(
+        body,
+        json!({"name": "foo", "rounding": "cent", "group_id": null, "settled_at": null})
+    );
     assert!(
         DateTime::parse_from_rfc3339(&created_at).is_ok(),
         "created_at wasn't a valid datetime, it was {}",
@@ -320,7 +343,11 @@ async fn outings() {
 
     assert_eq!(
         body,
-        json!([{"name": "foo"}, {"name": "bar"}, {"name": "baz"}])
+        json!([
+            {"name": "foo", "rounding": "cent", "group_id": null, "settled_at": null},
+            {"name": "bar", "rounding": "cent", "group_id": null, "settled_at": null},
+            {"name": "baz", "rounding": "cent", "group_id": null, "settled_at": null}
+        ])
     );
 
     cleanup(pool, "outings").await;
@@ -350,6 +377,17 @@ async fn post_expense(pool: &PgPool, inp: &Value) -> Response {
     response
 }
 
+/// The IDs of the expenses listed at `uri`, in order
+async fn expense_ids(pool: &PgPool, uri: &str) -> Vec<i64> {
+    get_json(pool, uri)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|expense| expense["expense_id"].as_i64().unwrap())
+        .collect()
+}
+
 #[tokio::test]
 async fn expenses() {
     let pool = setup_test_db("expenses").await;
@@ -368,7 +406,10 @@ async fn expenses() {
         "outing_id": &outing_id,
         "person_name": &person_name,
         "amount": &amount,
-        "description": &desc
+        "description": &desc,
+        "category": " Food ",
+        "tags": [" Dinner ", "dinner", "", "Team"],
+        "incurred_at": "2023-05-30"
     });
     let response = post_expense(&pool, &inp).await;
     let body = body_bytes(response).await;
@@ -386,7 +427,37 @@ async fn expenses() {
         &created_at
     );
 
-    assert_eq!(body_parsed, inp);
+    // Amounts come back as exact decimal strings, and categories normalized
+    let mut expt = inp.clone();
+    expt["amount"] = json!("24.6500");
+    expt["category"] = json!("food");
+    expt["tags"] = json!(["dinner", "team"]);
+    expt["kind"] = json!("purchase");
+    expt["recipient"] = Value::Null;
+    assert_eq!(body_parsed, expt);
+
+    // Amounts with more precision than we can store are refused rather than
+    // silently rounded
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/api/expenses")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "outing_id": &outing_id,
+                        "person_name": &person_name,
+                        "amount": 1.23456
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
     // Check the outing /balance and /expenses routes
 
@@ -426,6 +497,26 @@ async fn expenses() {
     let body = body_bytes(response).await;
     let body_parsed: Value = serde_json::from_slice(&body).unwrap();
 
+    assert_eq!(body_parsed, json!({ "total": "68.7200" }));
+
+    // Clients can still ask for the old float format
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/outings/{}/balance", &outing_id))
+                .header(http::header::ACCEPT, "application/json; amounts=float")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = body_bytes(response).await;
+    let body_parsed: Value = serde_json::from_slice(&body).unwrap();
+
     assert_eq!(body_parsed, json!({ "total": &total }));
 
     let response = get_app(&pool)
@@ -444,23 +535,77 @@ async fn expenses() {
     let body = body_bytes(response).await;
     let mut body_parsed: Value = serde_json::from_slice(&body).unwrap();
     for val in body_parsed.as_array_mut().unwrap() {
-        let created_at = get_string_key(val.as_object_mut().unwrap(), "created_at");
-        assert!(
-            DateTime::parse_from_rfc3339(&created_at).is_ok(),
-            "created_at wasn't a valid datetime, it was {}",
-            &created_at
-        );
+        let map = val.as_object_mut().unwrap();
+        let created_at = get_string_key(map, "created_at");
+        let created_at = DateTime::parse_from_rfc3339(&created_at).unwrap_or_else(|_| {
+            panic!("created_at wasn't a valid datetime, it was {}", &created_at)
+        });
+
+        // Expenses without an incurred date default to the day they're added
+        if map["expense_id"] != json!(1) {
+            let incurred_at = get_string_key(map, "incurred_at");
+            assert_eq!(
+                incurred_at,
+                created_at.naive_utc().date().to_string(),
+                "incurred_at didn't default to the created_at date"
+            );
+        }
     }
 
     assert_eq!(
         body_parsed,
         json!([
-            { "expense_id": 1, "outing_id": &outing_id, "person_name": &person_name, "amount": &amount, "description": &desc },
-            { "expense_id": 2, "outing_id": &outing_id, "person_name": &person_two, "amount": &amount_two, "description": null },
-            { "expense_id": 3, "outing_id": &outing_id, "person_name": &person_three, "amount": &amount_three, "description": null }
+            { "expense_id": 1, "outing_id": &outing_id, "person_name": &person_name, "amount": "24.6500", "description": &desc, "category": "food", "tags": ["dinner", "team"], "incurred_at": "2023-05-30", "kind": "purchase", "recipient": null },
+            { "expense_id": 2, "outing_id": &outing_id, "person_name": &person_two, "amount": "19.0200", "description": null, "category": null, "tags": [], "kind": "purchase", "recipient": null },
+            { "expense_id": 3, "outing_id": &outing_id, "person_name": &person_three, "amount": "25.0500", "description": null, "category": null, "tags": [], "kind": "purchase", "recipient": null }
         ])
     );
 
+    // Listings can be narrowed down to a range of incurred dates
+    let expenses = |query: &str| format!("/api/outings/{}/expenses?{}", &outing_id, query);
+    let ids = expense_ids(&pool, &expenses("from=2023-05-01&to=2023-05-31")).await;
+    assert_eq!(ids, vec![1]);
+    let ids = expense_ids(&pool, &expenses("to=2023-05-29")).await;
+    assert_eq!(ids, Vec::<i64>::new());
+    let ids = expense_ids(&pool, &expenses("from=2023-05-31")).await;
+    assert_eq!(ids, vec![2, 3]);
+
+    // ...or by who paid and how much
+    let ids = expense_ids(&pool, &expenses("payer=person%20B")).await;
+    assert_eq!(ids, vec![2]);
+    let ids = expense_ids(&pool, &expenses("min_amount=20")).await;
+    assert_eq!(ids, vec![1, 3]);
+    let ids = expense_ids(&pool, &expenses("min_amount=19.02&max_amount=24.65")).await;
+    assert_eq!(ids, vec![1, 2]);
+
+    // ...and fetched a page at a time
+    let ids = expense_ids(&pool, &expenses("limit=2")).await;
+    assert_eq!(ids, vec![1, 2]);
+    let ids = expense_ids(&pool, &expenses("limit=2&offset=2")).await;
+    assert_eq!(ids, vec![3]);
+    let ids = expense_ids(&pool, &expenses("min_amount=20&limit=1&offset=1")).await;
+    assert_eq!(ids, vec![3]);
+
+    for query in [
+        "from=yesterday",
+        "min_amount=lots",
+        "limit=0",
+        "limit=501",
+        "offset=-1",
+    ] {
+        let response = get_app(&pool)
+            .await
+            .oneshot(
+                Request::builder()
+                    .uri(expenses(query))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", query);
+    }
+
     // Test the final output of outing results
     let response = get_app(&pool)
         .await
@@ -479,17 +624,2137 @@ async fn expenses() {
     let body_parsed: Value = serde_json::from_slice(&body).unwrap();
 
     // expected:
-    // B pays 3.8867 to C
-    // C pays 1.7434 to A
+    // B pays 3.89 to C
+    // C pays 1.74 to A
     assert_eq!(
         body_parsed,
+        json!({
+            "rounding": "cent",
+            "results": [
+                { "from": &person_two, "to": &person_three, "amount": "3.89" },
+                { "from": &person_three, "to": &person_name, "amount": "1.74" }
+            ]
+        })
+    );
+
+    cleanup(pool, "expenses").await;
+}
+
+#[tokio::test]
+async fn expense_kinds() {
+    let pool = setup_test_db("expense_kinds").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('foo'); \
+         INSERT INTO outing_people(outing_id, name) VALUES \
+           (1, 'person A'), (1, 'person B'), (1, 'person C');",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let expense = |person_name: &str, amount: &str, kind: &str, recipient: Option<&str>| {
+        json!({
+            "outing_id": &outing_id,
+            "person_name": person_name,
+            "amount": amount,
+            "kind": kind,
+            "recipient": recipient
+        })
+    };
+
+    // A buys dinner, B returns something and gets the money back, and C pays A
+    // back directly. The refund comes off what the outing spent, and the
+    // transfer only concerns A and C.
+    for inp in [
+        expense("person A", "30", "purchase", None),
+        expense("person B", "6", "refund", None),
+        expense("person C", "10", "transfer", Some("person A")),
+    ] {
+        post_expense(&pool, &inp).await;
+    }
+
+    let balance = get_json(&pool, &format!("/api/outings/{}/balance", &outing_id)).await;
+    assert_eq!(balance, json!({ "total": "24.0000" }));
+
+    let summary = get_json(&pool, &format!("/api/outings/{}/summary", &outing_id)).await;
+    assert_eq!(summary["total"], json!("24.0000"));
+    assert_eq!(summary["categories"][0]["expense_count"], json!(2));
+
+    // Everyone's share is 8: A is out 20, B is up 6 and C is out 10, so B owes
+    // 14, A is owed 12 and C is owed 2
+    let results = get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await;
+    assert_eq!(
+        results["results"],
         json!([
-            { "from": &person_two, "to": &person_three, "amount": 3.8867 },
-            { "from": &person_three, "to": &person_name, "amount": 1.7434 }
+            { "from": "person B", "to": "person A", "amount": "14.00" },
+            { "from": "person A", "to": "person C", "amount": "2.00" }
         ])
     );
 
-    cleanup(pool, "expenses").await;
+    // Previewing a transfer works the same way
+    let response = post_preview(
+        &pool,
+        &outing_id,
+        &json!({ "expenses": [expense("person B", "12", "transfer", Some("person A"))] }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let body_parsed: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body_parsed["results"],
+        json!([{ "from": "person B", "to": "person C", "amount": "2.00" }])
+    );
+
+    // Negative purchases are how refunds used to be entered
+    let response = post_expense(&pool, &expense("person C", "-3", "purchase", None)).await;
+    let body = body_bytes(response).await;
+    let body_parsed: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body_parsed["kind"], json!("refund"));
+    assert_eq!(body_parsed["amount"], json!("3.0000"));
+
+    for inp in [
+        expense("person A", "5", "transfer", None),
+        expense("person A", "5", "transfer", Some("person A")),
+        expense("person A", "5", "purchase", Some("person B")),
+        expense("person A", "-5", "refund", None),
+        expense("person A", "-5", "transfer", Some("person B")),
+    ] {
+        let response = post_json(&pool, "/api/expenses", &inp).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", inp);
+    }
+
+    cleanup(pool, "expense_kinds").await;
+}
+
+#[tokio::test]
+async fn outing_transfers() {
+    let pool = setup_test_db("outing_transfers").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('foo'); \
+         INSERT INTO outing_people(outing_id, name) VALUES \
+           (1, 'person A'), (1, 'person B'), (1, 'person C'); \
+         INSERT INTO expenses(outing_id, person_name, amount) VALUES (1, 'person A', 30);",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let transfers = format!("/api/outings/{}/transfers", &outing_id);
+
+    // A hands B 20 in cash
+    let response = post_json(
+        &pool,
+        &transfers,
+        &json!({ "from": "person A", "to": "person B", "amount": "20", "description": "cash" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let mut body_parsed: Value = serde_json::from_slice(&body).unwrap();
+    let map = body_parsed.as_object_mut().unwrap();
+    get_string_key(map, "created_at");
+    get_string_key(map, "incurred_at");
+    let transfer = json!({
+        "transfer_id": 2,
+        "outing_id": &outing_id,
+        "from": "person A",
+        "to": "person B",
+        "amount": "20.0000",
+        "description": "cash"
+    });
+    assert_eq!(body_parsed, transfer);
+
+    let mut listed = get_json(&pool, &transfers).await;
+    for t in listed.as_array_mut().unwrap() {
+        let map = t.as_object_mut().unwrap();
+        get_string_key(map, "created_at");
+        get_string_key(map, "incurred_at");
+    }
+    assert_eq!(listed, json!([transfer]));
+
+    // Transfers are kept apart from the shared expenses, and don't count
+    // towards what the outing spent
+    let ids = expense_ids(&pool, &format!("/api/outings/{}/expenses", &outing_id)).await;
+    assert_eq!(ids, vec![1]);
+    let balance = get_json(&pool, &format!("/api/outings/{}/balance", &outing_id)).await;
+    assert_eq!(balance, json!({ "total": "30.0000" }));
+
+    // B owes 10 for dinner and the 20 they were handed, while C only owes 10
+    let results = get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await;
+    assert_eq!(
+        results["results"],
+        json!([
+            { "from": "person B", "to": "person A", "amount": "30.00" },
+            { "from": "person C", "to": "person A", "amount": "10.00" }
+        ])
+    );
+
+    // The transfer isn't something either of them paid for
+    let people = get_json(&pool, &format!("/api/outings/{}/people", &outing_id)).await;
+    assert_eq!(
+        people,
+        json!([
+            { "name": "person A", "paid": "30.0000", "transferred": "20.0000", "share": "10.0000", "net": "40.0000", "expense_count": 1 },
+            { "name": "person B", "paid": "0.0000", "transferred": "-20.0000", "share": "10.0000", "net": "-30.0000", "expense_count": 0 },
+            { "name": "person C", "paid": "0.0000", "transferred": "0.0000", "share": "10.0000", "net": "-10.0000", "expense_count": 0 }
+        ])
+    );
+
+    // Whichever way a transfer is added, anybody not in the outing yet joins it
+    let response = post_json(
+        &pool,
+        &transfers,
+        &json!({ "from": "person A", "to": "person D", "amount": "5" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    post_expense(
+        &pool,
+        &json!({
+            "outing_id": &outing_id,
+            "person_name": "person E",
+            "amount": "5",
+            "kind": "transfer",
+            "recipient": "person F"
+        }),
+    )
+    .await;
+    let outing = get_json(&pool, &format!("/api/outings/{}", &outing_id)).await;
+    assert_eq!(
+        outing["people"],
+        json!(["person A", "person B", "person C", "person D", "person E", "person F"])
+    );
+
+    for inp in [
+        json!({ "from": "person A", "to": "person A", "amount": "5" }),
+        json!({ "from": "person A", "to": "person B", "amount": "-5" }),
+    ] {
+        let response = post_json(&pool, &transfers, &inp).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", inp);
+    }
+
+    let missing = birdie::models::HARSH.encode(&[99]);
+    let response = post_json(
+        &pool,
+        &format!("/api/outings/{}/transfers", missing),
+        &json!({ "from": "person A", "to": "person B", "amount": "5" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    cleanup(pool, "outing_transfers").await;
+}
+
+#[tokio::test]
+async fn itemized_bills() {
+    let pool = setup_test_db("itemized_bills").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('foo'); \
+         INSERT INTO outing_people(outing_id, name) VALUES \
+           (1, 'person A'), (1, 'person B'), (1, 'person C'); \
+         INSERT INTO expenses(outing_id, person_name, amount) VALUES (1, 'person C', 0);",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let bill = |person_name: &str, items: Value, extras: Value| {
+        json!({
+            "outing_id": &outing_id,
+            "person_name": person_name,
+            "description": "dinner",
+            "incurred_at": "2023-06-01",
+            "items": items,
+            "extras": extras
+        })
+    };
+
+    // The tax and tip are 23% of the food, so everybody pays 23% on top of
+    // what they ordered
+    let response = post_json(
+        &pool,
+        "/api/expenses/itemized",
+        &bill(
+            "person A",
+            json!([
+                { "person_name": "person A", "amount": "20", "description": "pasta" },
+                { "person_name": "person B", "amount": "10", "description": "salad" },
+                { "person_name": "person B", "amount": "5", "description": "drink" },
+                { "person_name": "person C", "amount": "15", "description": null }
+            ]),
+            json!([
+                { "amount": "4", "description": "tax" },
+                { "amount": "7.50", "description": "tip" }
+            ]),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let body_parsed: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body_parsed["expense_id"], json!(2));
+    assert_eq!(body_parsed["amount"], json!("61.5000"));
+    assert_eq!(
+        body_parsed["shares"],
+        json!([
+            { "person_name": "person A", "subtotal": "20.0000", "extras": "4.6000", "share": "24.6000" },
+            { "person_name": "person B", "subtotal": "15.0000", "extras": "3.4500", "share": "18.4500" },
+            { "person_name": "person C", "subtotal": "15.0000", "extras": "3.4500", "share": "18.4500" }
+        ])
+    );
+
+    // Extras that don't divide evenly still add up to exactly the total
+    let response = post_json(
+        &pool,
+        "/api/expenses/itemized",
+        &bill(
+            "person B",
+            json!([
+                { "person_name": "person A", "amount": "10" },
+                { "person_name": "person B", "amount": "10" },
+                { "person_name": "person C", "amount": "10" }
+            ]),
+            json!([{ "amount": "10", "description": "service fee" }]),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut items = get_json(&pool, "/api/expenses/3/items").await;
+    let map = items.as_object_mut().unwrap();
+    get_string_key(map, "created_at");
+    assert_eq!(
+        items,
+        json!({
+            "expense_id": 3,
+            "outing_id": &outing_id,
+            "person_name": "person B",
+            "amount": "40.0000",
+            "description": "dinner",
+            "category": null,
+            "tags": [],
+            "incurred_at": "2023-06-01",
+            "kind": "purchase",
+            "recipient": null,
+            "items": [
+                { "person_name": "person A", "amount": "10.0000", "description": null },
+                { "person_name": "person B", "amount": "10.0000", "description": null },
+                { "person_name": "person C", "amount": "10.0000", "description": null }
+            ],
+            "extras": [{ "amount": "10.0000", "description": "service fee" }],
+            "shares": [
+                { "person_name": "person A", "subtotal": "10.0000", "extras": "3.3334", "share": "13.3334" },
+                { "person_name": "person B", "subtotal": "10.0000", "extras": "3.3333", "share": "13.3333" },
+                { "person_name": "person C", "subtotal": "10.0000", "extras": "3.3333", "share": "13.3333" }
+            ]
+        })
+    );
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri("/api/expenses/1/items")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Settlement goes by the shares rather than splitting the bills evenly:
+    // A is up 61.50 - 24.60 - 13.3334, B is up 40 - 18.45 - 13.3333, and C is
+    // down 18.45 + 13.3333
+    let balance = get_json(&pool, &format!("/api/outings/{}/balance", &outing_id)).await;
+    assert_eq!(balance, json!({ "total": "101.5000" }));
+    let results = get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await;
+    assert_eq!(
+        results["results"],
+        json!([
+            { "from": "person C", "to": "person A", "amount": "31.78" },
+            { "from": "person A", "to": "person B", "amount": "8.22" }
+        ])
+    );
+
+    // Nobody pays a negative amount for their food: what they ordered goes
+    // into their share instead
+    let people = json!([
+        { "name": "person A", "paid": "61.5000", "transferred": "0.0000", "share": "37.9400", "net": "23.5600", "expense_count": 1 },
+        { "name": "person B", "paid": "40.0000", "transferred": "0.0000", "share": "31.7800", "net": "8.2200", "expense_count": 1 },
+        { "name": "person C", "paid": "0.0000", "transferred": "0.0000", "share": "31.7800", "net": "-31.7800", "expense_count": 1 }
+    ]);
+    assert_eq!(
+        get_json(&pool, &format!("/api/outings/{}/people", &outing_id)).await,
+        people
+    );
+    let explained = get_json(
+        &pool,
+        &format!("/api/outings/{}/finish?explain=true", &outing_id),
+    )
+    .await;
+    assert_eq!(explained["explanation"]["people"], people);
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/outings/{}/export/splitwise", &outing_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = body_bytes(response).await;
+    let splitwise = std::str::from_utf8(&body).unwrap();
+    assert!(
+        splitwise.contains("2023-06-01,dinner,General,61.50,USD,36.90,-18.45,-18.45\n"),
+        "{}",
+        splitwise
+    );
+    assert!(
+        splitwise.contains("2023-06-01,dinner,General,40.00,USD,-13.34,26.67,-13.33\n"),
+        "{}",
+        splitwise
+    );
+
+    for inp in [
+        bill("person A", json!([]), json!([])),
+        bill(
+            "person A",
+            json!([{ "person_name": "person A", "amount": "0" }]),
+            json!([{ "amount": "5" }]),
+        ),
+        bill(
+            "person A",
+            json!([{ "person_name": "person A", "amount": "10" }]),
+            json!([{ "amount": "-5" }]),
+        ),
+        bill(
+            "person A",
+            json!([{ "person_name": "person Z", "amount": "10" }]),
+            json!([]),
+        ),
+    ] {
+        let response = post_json(&pool, "/api/expenses/itemized", &inp).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", inp);
+    }
+
+    // Archives keep the bills' lines, and the copy settles the same way
+    let exported = get_json(&pool, &format!("/api/outings/{}/export", &outing_id)).await;
+    assert_eq!(exported["expenses"][0].get("items"), None);
+    assert_eq!(
+        exported["expenses"][1]["items"].as_array().unwrap().len(),
+        4
+    );
+    assert_eq!(
+        exported["expenses"][1]["extras"].as_array().unwrap().len(),
+        2
+    );
+
+    let response = post_json(&pool, "/api/outings/import", &exported).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let imported: Value = serde_json::from_slice(&body).unwrap();
+    let new_id = imported["outing_id"].as_str().unwrap();
+
+    let reexported = get_json(&pool, &format!("/api/outings/{}/export", new_id)).await;
+    assert_eq!(reexported, exported);
+
+    // A bill whose shares all round to nothing still goes to Splitwise
+    let mut crumbs = bill(
+        "person A",
+        json!([
+            { "person_name": "person A", "amount": "0.004" },
+            { "person_name": "person B", "amount": "0.004" },
+            { "person_name": "person C", "amount": "0.004" }
+        ]),
+        json!([]),
+    );
+    crumbs["description"] = json!("crumbs");
+    let response = post_json(&pool, "/api/expenses/itemized", &crumbs).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/outings/{}/export/splitwise", &outing_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let splitwise = std::str::from_utf8(&body).unwrap();
+    assert!(
+        splitwise.contains("2023-06-01,crumbs,General,0.01,USD,0.00,0.00,0.00\n"),
+        "{}",
+        splitwise
+    );
+
+    cleanup(pool, "itemized_bills").await;
+}
+
+#[tokio::test]
+async fn settlement_rounding() {
+    let pool = setup_test_db("settlement_rounding").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('thirds'), ('square'), ('math.org'); \
+         INSERT INTO outing_people(outing_id, name) VALUES \
+           (1, 'person A'), (1, 'person B'), (1, 'person C'), \
+           (2, 'person A'), (2, 'person B'), (2, 'person C'), (2, 'person D'), \
+           (3, 'A'), (3, 'B'), (3, 'C'), (3, 'D'); \
+         INSERT INTO expenses(outing_id, person_name, amount) VALUES \
+           (1, 'person A', 10), \
+           (2, 'person A', 20), (2, 'person B', 10), (2, 'person C', 10), \
+           (3, 'A', 24.65), (3, 'B', 19.02), (3, 'C', 25.05), (3, 'D', 20);",
+    )
+    .await
+    .unwrap();
+
+    // Nobody can pay a third of a cent, so the leftover cent goes to whoever
+    // lost the most in rounding, and the transfers still add up exactly
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let results = get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await;
+    assert_eq!(
+        results,
+        json!({
+            "rounding": "cent",
+            "results": [
+                { "from": "person C", "to": "person A", "amount": "3.33" },
+                { "from": "person B", "to": "person A", "amount": "3.33" }
+            ]
+        })
+    );
+
+    // Per-person balances agree with the settlement, down to the cent
+    let people = get_json(&pool, &format!("/api/outings/{}/people", &outing_id)).await;
+    assert_eq!(
+        people,
+        json!([
+            { "name": "person A", "paid": "10.0000", "transferred": "0.0000", "share": "3.3400", "net": "6.6600", "expense_count": 1 },
+            { "name": "person B", "paid": "0.0000", "transferred": "0.0000", "share": "3.3300", "net": "-3.3300", "expense_count": 0 },
+            { "name": "person C", "paid": "0.0000", "transferred": "0.0000", "share": "3.3300", "net": "-3.3300", "expense_count": 0 }
+        ])
+    );
+
+    // Each rounding policy rounds differently, but always adds up exactly
+    for (rounding, expt) in [
+        (
+            "bankers",
+            json!([
+                { "from": "person C", "to": "person A", "amount": "3.33" },
+                { "from": "person B", "to": "person A", "amount": "3.33" }
+            ]),
+        ),
+        (
+            "payers_up",
+            json!([
+                { "from": "person C", "to": "person A", "amount": "3.34" },
+                { "from": "person B", "to": "person A", "amount": "3.34" }
+            ]),
+        ),
+        (
+            "nickel",
+            json!([
+                { "from": "person B", "to": "person A", "amount": "3.35" },
+                { "from": "person C", "to": "person A", "amount": "3.30" }
+            ]),
+        ),
+        (
+            "whole",
+            json!([
+                { "from": "person C", "to": "person A", "amount": "3" },
+                { "from": "person B", "to": "person A", "amount": "3" }
+            ]),
+        ),
+    ] {
+        let response = get_app(&pool)
+            .await
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::PUT)
+                    .uri(format!("/api/outings/{}/rounding", &outing_id))
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({ "rounding": rounding })).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let results = get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await;
+        assert_eq!(results, json!({ "rounding": rounding, "results": expt }));
+    }
+
+    // People who are already square don't get any zero-dollar transfers
+    let outing_id = birdie::models::HARSH.encode(&[2]);
+    let results = get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await;
+    assert_eq!(
+        results["results"],
+        json!([{ "from": "person D", "to": "person A", "amount": "10.00" }])
+    );
+
+    // The explanation walks through the same steps as the worked example in
+    // math.org
+    let outing_id = birdie::models::HARSH.encode(&[3]);
+    let plain = get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await;
+    assert!(plain.get("explanation").is_none());
+    let results = get_json(
+        &pool,
+        &format!("/api/outings/{}/finish?explain=true", &outing_id),
+    )
+    .await;
+    assert_eq!(results["results"], plain["results"]);
+    assert_eq!(
+        results["explanation"]["people"][1],
+        json!({ "name": "B", "paid": "19.0200", "transferred": "0.0000", "share": "22.1800", "net": "-3.1600", "expense_count": 1 })
+    );
+    assert_eq!(
+        results["explanation"]["steps"],
+        json!([
+            {
+                "before": [
+                    { "name": "B", "owes": "3.16" },
+                    { "name": "D", "owes": "2.18" },
+                    { "name": "A", "owes": "-2.47" },
+                    { "name": "C", "owes": "-2.87" }
+                ],
+                "from": "B", "to": "C", "amount": "3.16"
+            },
+            {
+                "before": [
+                    { "name": "D", "owes": "2.18" },
+                    { "name": "C", "owes": "0.29" },
+                    { "name": "A", "owes": "-2.47" }
+                ],
+                "from": "D", "to": "A", "amount": "2.18"
+            },
+            {
+                "before": [
+                    { "name": "C", "owes": "0.29" },
+                    { "name": "A", "owes": "-0.29" }
+                ],
+                "from": "C", "to": "A", "amount": "0.29"
+            }
+        ])
+    );
+
+    // Settling through a treasurer instead
+    let results = get_json(
+        &pool,
+        &format!(
+            "/api/outings/{}/finish?strategy=hub&treasurer=D",
+            &outing_id
+        ),
+    )
+    .await;
+    assert_eq!(
+        results["results"],
+        json!([
+            { "from": "B", "to": "D", "amount": "3.16" },
+            { "from": "D", "to": "C", "amount": "2.87" },
+            { "from": "D", "to": "A", "amount": "2.47" }
+        ])
+    );
+
+    for uri in [
+        format!("/api/outings/{}/finish?strategy=hub", &outing_id),
+        format!(
+            "/api/outings/{}/finish?strategy=hub&treasurer=E",
+            &outing_id
+        ),
+    ] {
+        let response = get_app(&pool)
+            .await
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // The database won't store a policy we couldn't read back either
+    let result = pool
+        .execute("UPDATE outings SET rounding = 'dime' WHERE outing_id = 1")
+        .await;
+    assert!(result.is_err());
+
+    cleanup(pool, "settlement_rounding").await;
+}
+
+async fn post_preview(pool: &PgPool, outing_id: &str, inp: &Value) -> Response {
+    get_app(pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(format!("/api/outings/{}/finish/preview", outing_id))
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(inp).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn finish_preview() {
+    let pool = setup_test_db("finish_preview").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('preview'), ('other'); \
+         INSERT INTO outing_people(outing_id, name) VALUES \
+           (1, 'person A'), (1, 'person B'), (2, 'person A'); \
+         INSERT INTO expenses(outing_id, person_name, amount) VALUES \
+           (1, 'person A', 30), (1, 'person B', 10), (2, 'person A', 5);",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let other_id = birdie::models::HARSH.encode(&[2]);
+
+    // An empty preview is the same as finishing the outing
+    let response = post_preview(&pool, &outing_id, &json!({})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let results: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(
+        results,
+        get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await
+    );
+
+    // Somebody new paying, with person B's expense left out
+    let response = post_preview(
+        &pool,
+        &outing_id,
+        &json!({
+            "expenses": [{
+                "outing_id": &outing_id,
+                "person_name": "person C",
+                "amount": "30",
+                "description": "Big bill",
+            }],
+            "exclude": [2],
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let results: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(
+        results,
+        json!({
+            "rounding": "cent",
+            "results": [
+                { "from": "person B", "to": "person A", "amount": "20.00" },
+                { "from": "person A", "to": "person C", "amount": "10.00" }
+            ]
+        })
+    );
+
+    // Nothing was actually saved
+    let expenses = get_json(&pool, &format!("/api/outings/{}/expenses", &outing_id)).await;
+    assert_eq!(expenses.as_array().unwrap().len(), 2);
+    let outing = get_json(&pool, &format!("/api/outings/{}", &outing_id)).await;
+    assert_eq!(outing["people"], json!(["person A", "person B"]));
+
+    // Excluding another outing's expense is an error, not a no-op
+    let response = post_preview(&pool, &outing_id, &json!({ "exclude": [3] })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = post_preview(
+        &pool,
+        &outing_id,
+        &json!({
+            "expenses": [{ "outing_id": &other_id, "person_name": "person A", "amount": "1" }],
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = post_preview(
+        &pool,
+        &outing_id,
+        &json!({
+            "expenses": [{ "outing_id": &outing_id, "person_name": "person A", "amount": "1.00001" }],
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = post_preview(&pool, &birdie::models::HARSH.encode(&[99]), &json!({})).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    cleanup(pool, "finish_preview").await;
+}
+
+async fn put_constraints(pool: &PgPool, outing_id: &str, inp: &Value) -> Response {
+    get_app(pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/api/outings/{}/constraints", outing_id))
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(inp).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn settlement_constraints() {
+    let pool = setup_test_db("settlement_constraints").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('constrained'); \
+         INSERT INTO outing_people(outing_id, name) VALUES (1, 'P'), (1, 'Q'), (1, 'R'), (1, 'S'); \
+         INSERT INTO expenses(outing_id, person_name, amount) VALUES (1, 'P', 40);",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let constraints_uri = format!("/api/outings/{}/constraints", &outing_id);
+    let finish_uri = format!("/api/outings/{}/finish", &outing_id);
+
+    assert_eq!(get_json(&pool, &constraints_uri).await, json!([]));
+
+    // Q can't pay P, who is the only one owed anything, so Q goes through
+    // somebody else who has to pay P anyway
+    let forbidden = json!({ "payer": "Q", "payee": "P", "kind": "forbidden" });
+    let response = put_constraints(&pool, &outing_id, &json!([forbidden])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(get_json(&pool, &constraints_uri).await, json!([forbidden]));
+    assert_eq!(
+        get_json(&pool, &finish_uri).await["results"],
+        json!([
+            { "from": "Q", "to": "R", "amount": "10.00" },
+            { "from": "R", "to": "P", "amount": "20.00" },
+            { "from": "S", "to": "P", "amount": "10.00" }
+        ])
+    );
+
+    // Preferred pairs get used first
+    let preferred = json!({ "payer": "Q", "payee": "S", "kind": "preferred" });
+    let response = put_constraints(&pool, &outing_id, &json!([forbidden, preferred])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        get_json(&pool, &finish_uri).await["results"],
+        json!([
+            { "from": "Q", "to": "S", "amount": "10.00" },
+            { "from": "S", "to": "P", "amount": "20.00" },
+            { "from": "R", "to": "P", "amount": "10.00" }
+        ])
+    );
+
+    // Settling through a treasurer still can't break the constraints
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!("{}?strategy=hub&treasurer=Q", &finish_uri))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // When Q can't pay anybody at all, there's no way to settle up
+    let response = put_constraints(
+        &pool,
+        &outing_id,
+        &json!([
+            forbidden,
+            { "payer": "Q", "payee": "R", "kind": "forbidden" },
+            { "payer": "Q", "payee": "S", "kind": "forbidden" }
+        ]),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(&finish_uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let message = String::from_utf8(body_bytes(response).await.to_vec()).unwrap();
+    assert!(message.contains("Q would still owe 10.00"), "{}", message);
+
+    // Constraints have to be between two different people in the outing
+    for bad in [
+        json!([{ "payer": "Q", "payee": "Nobody", "kind": "forbidden" }]),
+        json!([{ "payer": "Q", "payee": "Q", "kind": "preferred" }]),
+    ] {
+        let response = put_constraints(&pool, &outing_id, &bad).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    cleanup(pool, "settlement_constraints").await;
+}
+
+#[tokio::test]
+async fn settlement_constraint_relays() {
+    let pool = setup_test_db("settlement_constraint_relays").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('relays'); \
+         INSERT INTO outing_people(outing_id, name) VALUES (1, 'A'), (1, 'B'), (1, 'C'), (1, 'D'); \
+         INSERT INTO expenses(outing_id, person_name, amount) VALUES \
+           (1, 'B', 10), (1, 'C', 10), (1, 'D', 20);",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+
+    // A can only pay B, and B can't pay D either, so the debt has to go all
+    // the way around through C without B handing it straight back to A
+    let response = put_constraints(
+        &pool,
+        &outing_id,
+        &json!([
+            { "payer": "A", "payee": "C", "kind": "forbidden" },
+            { "payer": "A", "payee": "D", "kind": "forbidden" },
+            { "payer": "B", "payee": "D", "kind": "forbidden" }
+        ]),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        get_json(&pool, &format!("/api/outings/{}/finish", &outing_id)).await["results"],
+        json!([
+            { "from": "A", "to": "B", "amount": "10.00" },
+            { "from": "B", "to": "C", "amount": "10.00" },
+            { "from": "C", "to": "D", "amount": "10.00" }
+        ])
+    );
+
+    cleanup(pool, "settlement_constraint_relays").await;
+}
+
+async fn post_json(pool: &PgPool, uri: &str, inp: &Value) -> Response {
+    get_app(pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(inp).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn group_settlement() {
+    let pool = setup_test_db("group_settlement").await;
+
+    let response = post_json(&pool, "/api/groups", &json!({ "name": "team" })).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let group: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    let group_id = group["group_id"].as_str().unwrap().to_string();
+    assert_eq!(group["name"], "team");
+
+    // Two outings created in the group, and one moved into it afterwards
+    let mut outing_ids = vec![];
+    for (name, group) in [
+        ("lunch", json!(group_id)),
+        ("dinner", json!(group_id)),
+        ("drinks", Value::Null),
+    ] {
+        let response = post_json(
+            &pool,
+            "/api/outings",
+            &json!({ "name": name, "person_name": "person A", "group_id": group }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let outing: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(outing["group_id"], group);
+        outing_ids.push(outing["outing_id"].as_str().unwrap().to_string());
+    }
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/api/outings/{}/group", &outing_ids[2]))
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({ "group_id": &group_id })).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    // Lunch: A paid 30 for A, B and C. Dinner: B paid 20 for A and B.
+    // Drinks: C paid 9 for A, B and C.
+    for (outing_id, person_name, amount) in [
+        (&outing_ids[0], "person A", "30"),
+        (&outing_ids[0], "person B", "0"),
+        (&outing_ids[0], "person C", "0"),
+        (&outing_ids[1], "person B", "20"),
+        (&outing_ids[2], "person B", "0"),
+        (&outing_ids[2], "person C", "9"),
+    ] {
+        let response = post_expense(
+            &pool,
+            &json!({ "outing_id": outing_id, "person_name": person_name, "amount": amount }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let group = get_json(&pool, &format!("/api/groups/{}", &group_id)).await;
+    assert_eq!(group["outings"].as_array().unwrap().len(), 3);
+
+    // A: -20 + 10 + 3 = -7, B: 10 - 10 + 3 = 3, C: 10 + 0 - 6 = 4
+    let expt = json!({
+        "outings": outing_ids,
+        "results": [
+            { "from": "person C", "to": "person A", "amount": "4.00" },
+            { "from": "person B", "to": "person A", "amount": "3.00" }
+        ]
+    });
+    let finish_uri = format!("/api/groups/{}/finish", &group_id);
+    assert_eq!(get_json(&pool, &finish_uri).await, expt);
+
+    // Settling marks the outings so they don't get counted again
+    let response = post_json(
+        &pool,
+        &format!("/api/groups/{}/settle", &group_id),
+        &json!({}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let settled: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(settled, expt);
+
+    let outing = get_json(&pool, &format!("/api/outings/{}", &outing_ids[0])).await;
+    assert!(DateTime::parse_from_rfc3339(outing["settled_at"].as_str().unwrap()).is_ok());
+    assert_eq!(
+        get_json(&pool, &finish_uri).await,
+        json!({ "outings": [], "results": [] })
+    );
+
+    // Nothing more can be added to settled outings, since it would never be
+    // settled up
+    let settled_id = &outing_ids[0];
+    let response = post_json(
+        &pool,
+        "/api/expenses",
+        &json!({ "outing_id": settled_id, "person_name": "person B", "amount": "5" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = post_json(
+        &pool,
+        &format!("/api/outings/{}/transfers", settled_id),
+        &json!({ "from": "person B", "to": "person A", "amount": "5" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/api/groups/{}/finish",
+                    birdie::models::HARSH.encode(&[99])
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    cleanup(pool, "group_settlement").await;
+}
+
+#[tokio::test]
+async fn group_members() {
+    let pool = setup_test_db("group_members").await;
+
+    let response = post_json(
+        &pool,
+        "/api/groups",
+        &json!({
+            "name": "weekly",
+            "members": ["person A", "person B", " ", "person C", "person B"]
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let group: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    let group_id = group["group_id"].as_str().unwrap().to_string();
+    assert_eq!(
+        group["members"],
+        json!(["person A", "person B", "person C"])
+    );
+
+    // Everybody in the group is part of each outing created in it, along with
+    // whoever created it
+    for (person_name, expt) in [
+        (
+            "person D",
+            json!(["person A", "person B", "person C", "person D"]),
+        ),
+        ("person A", json!(["person A", "person B", "person C"])),
+    ] {
+        let response = post_json(
+            &pool,
+            "/api/outings",
+            &json!({ "name": "week", "person_name": person_name, "group_id": &group_id }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let outing: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+
+        let outing = get_json(
+            &pool,
+            &format!("/api/outings/{}", outing["outing_id"].as_str().unwrap()),
+        )
+        .await;
+        let mut people: Vec<String> = serde_json::from_value(outing["people"].clone()).unwrap();
+        people.sort();
+        assert_eq!(json!(people), expt);
+    }
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/api/groups/{}/join", &group_id))
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({ "name": "person E" })).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let group = get_json(&pool, &format!("/api/groups/{}", &group_id)).await;
+    let mut members: Vec<String> = serde_json::from_value(group["members"].clone()).unwrap();
+    members.sort();
+    assert_eq!(
+        members,
+        vec!["person A", "person B", "person C", "person E"]
+    );
+    assert_eq!(group["outings"].as_array().unwrap().len(), 2);
+
+    cleanup(pool, "group_members").await;
+}
+
+#[tokio::test]
+async fn recurring_expenses() {
+    let pool = setup_test_db("recurring_expenses").await;
+
+    let response = post_json(
+        &pool,
+        "/api/groups",
+        &json!({ "name": "house", "members": ["person A", "person B"] }),
+    )
+    .await;
+    let group: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    let group_id = group["group_id"].as_str().unwrap().to_string();
+    let recurring_uri = format!("/api/groups/{}/recurring", &group_id);
+
+    let response = post_json(
+        &pool,
+        &recurring_uri,
+        &json!({
+            "person_name": "person A",
+            "amount": "30",
+            "description": "Internet",
+            "schedule": "monthly",
+            "starts_on": "2026-01-31"
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let recurring: Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(recurring["amount"], "30.0000");
+    assert_eq!(recurring["starts_on"], "2026-01-31");
+
+    for amount in ["1.00001", "-10"] {
+        let response = post_json(
+            &pool,
+            &recurring_uri,
+            &json!({ "person_name": "person B", "amount": amount, "schedule": "weekly" }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(
+        get_json(&pool, &recurring_uri)
+            .await
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // A negative recurring expense from before they were refused can never be
+    // added, but it doesn't stop the others from being added
+    pool.execute(
+        "ALTER TABLE recurring_expenses DROP CONSTRAINT recurring_expenses_amount_check; \
+         INSERT INTO recurring_expenses(recurring_id, group_id, person_name, amount, schedule, starts_on) \
+           VALUES (0, 1, 'person B', -10, 'weekly', '2026-01-01');",
+    )
+    .await
+    .unwrap();
+
+    // Every occurrence so far gets its own outing, and shorter months get the
+    // expense on their last day
+    let date = |s| chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let created = birdie::recurring::materialize(&pool, date("2026-03-31"))
+        .await
+        .unwrap();
+    assert_eq!(created.len(), 3);
+
+    let group = get_json(&pool, &format!("/api/groups/{}", &group_id)).await;
+    let names: Vec<&str> = group["outings"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|o| o["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "Internet (2026-01-31)",
+            "Internet (2026-02-28)",
+            "Internet (2026-03-31)"
+        ]
+    );
+
+    // Running it again, say after a restart, doesn't add anything twice
+    for today in ["2026-03-31", "2026-04-29"] {
+        let created = birdie::recurring::materialize(&pool, date(today))
+            .await
+            .unwrap();
+        assert!(created.is_empty());
+    }
+
+    let results = get_json(&pool, &format!("/api/groups/{}/finish", &group_id)).await;
+    assert_eq!(
+        results["results"],
+        json!([{ "from": "person B", "to": "person A", "amount": "45.00" }])
+    );
+
+    // Deleted recurring expenses stop coming around
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::DELETE)
+                .uri(format!("{}/{}", &recurring_uri, recurring["recurring_id"]))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let created = birdie::recurring::materialize(&pool, date("2026-06-30"))
+        .await
+        .unwrap();
+    assert!(created.is_empty());
+
+    cleanup(pool, "recurring_expenses").await;
+}
+
+#[tokio::test]
+async fn expense_search() {
+    let pool = setup_test_db("expense_search").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('road trip'), ('other'); \
+         INSERT INTO outing_people(outing_id, name) VALUES (1, 'person A'), (2, 'person A'); \
+         INSERT INTO expenses(outing_id, person_name, amount, description, tags) VALUES \
+           (1, 'person A', 40, 'Filled up at the gas station', '{fuel}'), \
+           (1, 'person A', 12, 'Dinner by the highway', '{food}'), \
+           (1, 'person A', 3, NULL, '{gas,snacks}'), \
+           (1, 'person A', 60, 'Motel', '{lodging}'), \
+           (2, 'person A', 50, 'Gas again', '{}'), \
+           (1, 'person A', 1, '<img src=x onerror=alert(1)> toll & tip', '{}');",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let search = |q: &str| format!("/api/outings/{}/search?q={}", &outing_id, q);
+
+    // Matches in descriptions rank above matches in tags, and only this
+    // outing's expenses are searched
+    let results = get_json(&pool, &search("gas")).await;
+    let results: Vec<Value> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| json!([r["expense_id"], r["headline"], r["matched_tags"]]))
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            json!([1, "Filled up at the <mark>gas</mark> station", []]),
+            json!([3, null, ["gas"]])
+        ]
+    );
+
+    // Web search syntax works, with stemming
+    let results = get_json(&pool, &search("%22gas%20station%22%20OR%20dinners")).await;
+    let ids: Vec<&Value> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| &r["expense_id"])
+        .collect();
+    assert_eq!(ids, vec![&json!(1), &json!(2)]);
+
+    let results = get_json(&pool, &search("lodging%20-motel")).await;
+    assert_eq!(results, json!([]));
+
+    // Headlines are meant to be shown as HTML, so anything else in the
+    // description gets escaped
+    let results = get_json(&pool, &search("toll")).await;
+    assert_eq!(
+        results[0]["headline"],
+        json!("&lt;img src=x onerror=alert(1)&gt; <mark>toll</mark> &amp; tip")
+    );
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(search("%20"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    cleanup(pool, "expense_search").await;
+}
+
+#[tokio::test]
+async fn csv_exports() {
+    let pool = setup_test_db("csv_exports").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('foo'); \
+         INSERT INTO outing_people(outing_id, name) VALUES \
+           (1, 'person C'), (1, 'person A'), (1, 'person B'); \
+         INSERT INTO expenses(created_at, outing_id, person_name, amount, description, category, tags) VALUES \
+           ('2023-06-01T12:00:00Z', 1, 'person A', 24.65, 'dinner, drinks', 'food', '{dinner,work}'), \
+           ('2023-06-01T13:30:00Z', 1, 'person B', 19.02, 'the \"good\" cab', 'transport', '{}'), \
+           ('2023-06-02T09:15:00Z', 1, 'person C', 25.05, NULL, NULL, '{}');",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/outings/{}/expenses.csv", &outing_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_TYPE],
+        "text/csv; charset=utf-8"
+    );
+    assert_eq!(
+        response.headers()[http::header::CONTENT_DISPOSITION],
+        format!("attachment; filename=\"{}-expenses.csv\"", &outing_id)
+    );
+
+    let body = body_bytes(response).await;
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        format!(
+            "expense_id,created_at,outing_id,person_name,amount,description,category,tags,incurred_at,kind,recipient\n\
+             1,2023-06-01T12:00:00+00:00,{0},person A,24.6500,\"dinner, drinks\",food,dinner;work,2023-06-01,purchase,\n\
+             2,2023-06-01T13:30:00+00:00,{0},person B,19.0200,\"the \"\"good\"\" cab\",transport,,2023-06-01,purchase,\n\
+             3,2023-06-02T09:15:00+00:00,{0},person C,25.0500,,,,2023-06-02,purchase,\n",
+            &outing_id
+        )
+    );
+
+    let summary = get_json(&pool, &format!("/api/outings/{}/summary", &outing_id)).await;
+    assert_eq!(
+        summary,
+        json!({
+            "total": "68.7200",
+            "categories": [
+                { "category": null, "total": "25.0500", "expense_count": 1 },
+                { "category": "food", "total": "24.6500", "expense_count": 1 },
+                { "category": "transport", "total": "19.0200", "expense_count": 1 }
+            ],
+            "people": [
+                { "person_name": "person A", "category": "food", "total": "24.6500", "expense_count": 1 },
+                { "person_name": "person B", "category": "transport", "total": "19.0200", "expense_count": 1 },
+                { "person_name": "person C", "category": null, "total": "25.0500", "expense_count": 1 }
+            ]
+        })
+    );
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/outings/{}/finish.csv", &outing_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        "from,to,amount\n\
+         person B,person C,3.89\n\
+         person C,person A,1.74\n"
+    );
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/outings/{}/export/splitwise", &outing_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let splitwise_expenses = "Date,Description,Category,Cost,Currency,person A,person B,person C\n\
+                              2023-06-01,\"dinner, drinks\",food,24.65,USD,16.43,-8.22,-8.21\n\
+                              2023-06-01,\"the \"\"good\"\" cab\",transport,19.02,USD,-6.34,12.68,-6.34\n\
+                              2023-06-02,,General,25.05,USD,-8.35,-8.35,16.70\n";
+    assert_eq!(std::str::from_utf8(&body).unwrap(), splitwise_expenses);
+
+    // Once everybody has paid up, the settlements come along as payments
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/api/outings/{}/export/splitwise?settled=true&currency=EUR",
+                    &outing_id
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let today = chrono::Utc::now().date_naive();
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        format!(
+            "{}\
+             {1},person B paid person C,Payment,3.89,EUR,0.00,3.89,-3.89\n\
+             {1},person C paid person A,Payment,1.74,EUR,-1.74,0.00,1.74\n",
+            splitwise_expenses.replace("USD", "EUR"),
+            today
+        )
+    );
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/api/outings/{}/export/ledger?asset_account=Assets:%7Bperson%7D\
+                     &receivable_account=Owed:%7Bouting%7D:%7Bperson%7D&currency=CAD",
+                    &outing_id
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_DISPOSITION],
+        format!("attachment; filename=\"{}-ledger.journal\"", &outing_id)
+    );
+    let body = body_bytes(response).await;
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        format!(
+            "; Birdie outing: foo\n\
+             \n\
+             2023-06-01 * dinner, drinks (person A)\n    \
+                 Assets:person A    -24.65 CAD\n    \
+                 Owed:foo:person A  8.22 CAD\n    \
+                 Owed:foo:person B  8.22 CAD\n    \
+                 Owed:foo:person C  8.21 CAD\n\
+             \n\
+             2023-06-01 * the \"good\" cab (person B)\n    \
+                 Assets:person B    -19.02 CAD\n    \
+                 Owed:foo:person A  6.34 CAD\n    \
+                 Owed:foo:person B  6.34 CAD\n    \
+                 Owed:foo:person C  6.34 CAD\n\
+             \n\
+             2023-06-02 * Paid by person C\n    \
+                 Assets:person C    -25.05 CAD\n    \
+                 Owed:foo:person A  8.35 CAD\n    \
+                 Owed:foo:person B  8.35 CAD\n    \
+                 Owed:foo:person C  8.35 CAD\n\
+             \n\
+             {0} * person B pays person C\n    \
+                 Assets:person C  3.89 CAD\n    \
+                 Assets:person B  -3.89 CAD\n\
+             \n\
+             {0} * person C pays person A\n    \
+                 Assets:person A  1.74 CAD\n    \
+                 Assets:person C  -1.74 CAD\n\
+             \n",
+            today
+        )
+    );
+
+    let missing = birdie::models::HARSH.encode(&[99]);
+    for export in ["expenses.csv", "finish.csv"] {
+        let response = get_app(&pool)
+            .await
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/api/outings/{}/{}", missing, export))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", export);
+    }
+
+    cleanup(pool, "csv_exports").await;
+}
+
+async fn post_import(
+    pool: &PgPool,
+    outing_id: &str,
+    query: &str,
+    content_type: &str,
+    body: &str,
+) -> (StatusCode, Value) {
+    let response = get_app(pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(format!("/api/outings/{}/import{}", outing_id, query))
+                .header(http::header::CONTENT_TYPE, content_type)
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let body = body_bytes(response).await;
+    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    (status, body)
+}
+
+async fn post_receipt(
+    pool: &PgPool,
+    expense_id: i32,
+    field: &str,
+    content_type: &str,
+    bytes: &[u8],
+) -> Response {
+    let boundary = "birdie-test-boundary";
+    let mut body = format!(
+        "--{boundary}\r\n\
+         Content-Disposition: form-data; name=\"{field}\"; filename=\"receipt\"\r\n\
+         Content-Type: {content_type}\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(bytes);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    get_app(pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(format!("/api/expenses/{}/receipt", expense_id))
+                .header(
+                    http::header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", boundary),
+                )
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn expense_receipts() {
+    let pool = setup_test_db("expense_receipts").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('foo'); \
+         INSERT INTO outing_people(outing_id, name) VALUES (1, 'person A'); \
+         INSERT INTO expenses(outing_id, person_name, amount) VALUES (1, 'person A', 24.65);",
+    )
+    .await
+    .unwrap();
+
+    let get_receipt = || async {
+        get_app(&pool)
+            .await
+            .oneshot(
+                Request::builder()
+                    .uri("/api/expenses/1/receipt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    };
+
+    assert_eq!(get_receipt().await.status(), StatusCode::NOT_FOUND);
+
+    let png = b"\x89PNG\r\n\x1A\nnot really a picture";
+    let response = post_receipt(&pool, 1, "receipt", "image/png", png).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let mut body_parsed: Value = serde_json::from_slice(&body).unwrap();
+    let uploaded_at = get_string_key(body_parsed.as_object_mut().unwrap(), "uploaded_at");
+    assert!(DateTime::parse_from_rfc3339(&uploaded_at).is_ok());
+    assert_eq!(
+        body_parsed,
+        json!({ "expense_id": 1, "content_type": "image/png", "size": png.len() })
+    );
+
+    let response = get_receipt().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[http::header::CONTENT_TYPE], "image/png");
+    assert_eq!(
+        response.headers()[http::header::X_CONTENT_TYPE_OPTIONS],
+        "nosniff"
+    );
+    assert_eq!(body_bytes(response).await, png.as_slice());
+
+    // Uploading again replaces the receipt
+    let jpeg = b"\xFF\xD8\xFF\xE0also not really a picture";
+    let response = post_receipt(&pool, 1, "receipt", "image/jpeg", jpeg).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = get_receipt().await;
+    assert_eq!(response.headers()[http::header::CONTENT_TYPE], "image/jpeg");
+    assert_eq!(body_bytes(response).await, jpeg.as_slice());
+
+    let response = post_receipt(&pool, 99, "receipt", "image/png", png).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = post_receipt(&pool, 1, "photo", "image/png", png).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Only images and PDFs, and they have to really be what they say they are
+    let response = post_receipt(&pool, 1, "receipt", "text/html", b"<script>").await;
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let response = post_receipt(&pool, 1, "receipt", "image/jpeg", png).await;
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let mut huge = jpeg.to_vec();
+    huge.resize(birdie::receipts::MAX_RECEIPT_SIZE + 1, 0);
+    let response = post_receipt(&pool, 1, "receipt", "image/jpeg", &huge).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // None of the failed uploads touched the receipt that was already there
+    assert_eq!(body_bytes(get_receipt().await).await, jpeg.as_slice());
+
+    cleanup(pool, "expense_receipts").await;
+}
+
+#[tokio::test]
+async fn import_expenses() {
+    let pool = setup_test_db("import_expenses").await;
+
+    pool.execute(
+        "INSERT INTO outings(name) VALUES ('foo'); \
+         INSERT INTO outing_people(outing_id, name) VALUES (1, 'person A');",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let count_expenses = || async {
+        let (n,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM expenses")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        n
+    };
+
+    // Every bad row is reported, and nothing is written
+    let (status, body) = post_import(
+        &pool,
+        &outing_id,
+        "",
+        "text/csv",
+        "person_name,amount,description\n\
+         person A,12.50,lunch\n\
+         ,3,nobody\n\
+         person B,lots,\n\
+         person B,1.23456,too precise\n",
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["expenses"], json!([]));
+    let errors = body["errors"].as_array().unwrap();
+    let error_rows: Vec<&Value> = errors.iter().map(|e| &e["row"]).collect();
+    assert_eq!(error_rows, vec![&json!(2), &json!(3), &json!(4)]);
+    assert_eq!(count_expenses().await, 0);
+
+    // Dry runs validate and report who would be added without writing
+    let csv = "description,person_name,amount\n\
+               \"lunch, with tip\",person A,12.50\n\
+               gas,person B,40.1234\n\
+               ,person C,-5\n";
+    let (status, body) = post_import(&pool, &outing_id, "?dry_run=true", "text/csv", csv).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        json!({
+            "dry_run": true,
+            "people_added": ["person B", "person C"],
+            "expenses": [],
+            "errors": []
+        })
+    );
+    assert_eq!(count_expenses().await, 0);
+
+    let (status, body) = post_import(&pool, &outing_id, "", "text/csv", csv).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["people_added"], json!(["person B", "person C"]));
+    let expenses: Vec<(Value, Value, Value)> = body["expenses"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| {
+            (
+                e["person_name"].clone(),
+                e["amount"].clone(),
+                e["description"].clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        expenses,
+        vec![
+            (
+                json!("person A"),
+                json!("12.5000"),
+                json!("lunch, with tip")
+            ),
+            (json!("person B"), json!("40.1234"), json!("gas")),
+            (json!("person C"), json!("5.0000"), Value::Null),
+        ]
+    );
+    // Negative amounts are how other apps write refunds
+    assert_eq!(body["expenses"][2]["kind"], json!("refund"));
+    assert_eq!(count_expenses().await, 3);
+
+    let sql_people: Vec<birdie::models::Named> =
+        sqlx::query_as("SELECT name FROM outing_people WHERE outing_id = 1 ORDER BY name")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(sql_people.len(), 3);
+
+    // JSON arrays work too
+    let (status, body) = post_import(
+        &pool,
+        &outing_id,
+        "",
+        mime::APPLICATION_JSON.as_ref(),
+        &json!([
+            { "person_name": "person D", "amount": 7.25 },
+            { "person_name": "person A", "amount": "3.10", "description": "snacks" }
+        ])
+        .to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["people_added"], json!(["person D"]));
+    assert_eq!(body["expenses"].as_array().unwrap().len(), 2);
+    assert_eq!(count_expenses().await, 5);
+
+    let (status, body) = post_import(
+        &pool,
+        &outing_id,
+        "",
+        mime::APPLICATION_JSON.as_ref(),
+        &json!([{ "person_name": "person D" }]).to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["errors"][0]["row"], json!(1));
+
+    let (status, _) = post_import(&pool, &outing_id, "", "text/plain", "hello").await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let missing_id = birdie::models::HARSH.encode(&[2]);
+    let (status, _) = post_import(&pool, &missing_id, "", "text/csv", csv).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    cleanup(pool, "import_expenses").await;
+}
+
+#[tokio::test]
+async fn archive_round_trip() {
+    let pool = setup_test_db("archive_round_trip").await;
+
+    pool.execute(
+        "INSERT INTO outings(created_at, name) VALUES ('2023-05-04T18:00:00Z', 'foo'); \
+         INSERT INTO outing_people(outing_id, name) VALUES \
+           (1, 'person A'), (1, 'person B'), (1, 'person C'); \
+         INSERT INTO expenses(created_at, outing_id, person_name, amount, description) VALUES \
+           ('2023-05-04T19:00:00Z', 1, 'person A', 24.65, 'dinner'), \
+           ('2023-05-04T20:15:00Z', 1, 'person B', 19.02, NULL), \
+           ('2023-05-05T08:30:00Z', 1, 'person C', 25.05, 'breakfast');",
+    )
+    .await
+    .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+    let exported = get_json(&pool, &format!("/api/outings/{}/export", &outing_id)).await;
+
+    // The archive format should deserialize into the documented type exactly
+    let archive: birdie::models::OutingArchive = serde_json::from_value(exported.clone()).unwrap();
+    assert_eq!(archive.version, birdie::models::ARCHIVE_VERSION);
+    assert_eq!(archive.people, vec!["person A", "person B", "person C"]);
+    assert_eq!(archive.expenses.len(), 3);
+    assert_eq!(exported["expenses"][0]["amount"], json!("24.6500"));
+    assert_eq!(serde_json::to_value(&archive).unwrap(), exported);
+
+    let response = get_app(&pool)
+        .await
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/api/outings/import")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(&exported).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let imported: Value = serde_json::from_slice(&body).unwrap();
+    let new_id = imported["outing_id"].as_str().unwrap();
+    assert_ne!(new_id, outing_id);
+    assert_eq!(imported["created_at"], json!("2023-05-04T18:00:00Z"));
+    assert_eq!(
+        imported["people"],
+        json!(["person A", "person B", "person C"])
+    );
+
+    // Exporting the copy gives back exactly what we put in
+    let reexported = get_json(&pool, &format!("/api/outings/{}/export", new_id)).await;
+    assert_eq!(reexported, exported);
+
+    // Archives from before expense kinds still import, as plain purchases
+    let mut old = exported.clone();
+    old["version"] = json!(1);
+    let response = post_json(&pool, "/api/outings/import", &old).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_bytes(response).await;
+    let imported: Value = serde_json::from_slice(&body).unwrap();
+    let old_id = imported["outing_id"].as_str().unwrap();
+    let reexported = get_json(&pool, &format!("/api/outings/{}/export", old_id)).await;
+    assert_eq!(reexported["version"], json!(2));
+    assert_eq!(reexported["expenses"], exported["expenses"]);
+
+    // Archives from the future, or with strangers paying for things, are refused
+    for bad in [
+        json!({ "version": 3, "outing": exported["outing"], "people": [], "expenses": [] }),
+        json!({ "version": 0, "outing": exported["outing"], "people": [], "expenses": [] }),
+        json!({
+            "version": 1,
+            "outing": exported["outing"],
+            "people": ["person A"],
+            "expenses": [{ "created_at": "2023-05-04T19:00:00Z", "person_name": "person Z", "amount": "1" }]
+        }),
+    ] {
+        let response = get_app(&pool)
+            .await
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/api/outings/import")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(serde_json::to_vec(&bad).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM outings")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 3);
+
+    cleanup(pool, "archive_round_trip").await;
+}
+
+async fn next_live_event<S>(socket: &mut S) -> Value
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let msg = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("timed out waiting for a live event")
+        .unwrap()
+        .unwrap();
+    serde_json::from_str(msg.to_text().unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn live_updates() {
+    let pool = setup_test_db("live_updates").await;
+
+    pool.execute("INSERT INTO outings(name) VALUES ('foo')")
+        .await
+        .unwrap();
+
+    let outing_id = birdie::models::HARSH.encode(&[1]);
+
+    // WebSockets need a real server, and every request has to go through the
+    // same router so that they all share one hub
+    let app = get_app(&pool).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_app = app.clone();
+    tokio::spawn(async move { axum::serve(listener, server_app).await.unwrap() });
+
+    let live_uri = format!("ws://{}/api/outings/{}/live", addr, &outing_id);
+    let (mut socket_a, _) = connect_async(&live_uri).await.unwrap();
+
+    // Hearing our own presence back means we're subscribed, so nothing after
+    // this can be missed
+    socket_a
+        .send(Message::Text(
+            json!({ "type": "presence", "name": "person A", "activity": null }).to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_live_event(&mut socket_a).await,
+        json!({ "id": 1, "type": "presence", "name": "person A", "activity": null })
+    );
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri(format!("/api/outings/{}/join", &outing_id))
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({ "name": "person A" })).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        next_live_event(&mut socket_a).await,
+        json!({ "id": 2, "type": "person_joined", "name": "person A" })
+    );
+
+    // Paying for something joins the outing too, but only people who weren't
+    // already in it get announced
+    for (name, amount) in [("person A", 12.5), ("person B", 7.5)] {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/api/expenses")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "outing_id": &outing_id,
+                            "person_name": name,
+                            "amount": amount
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let mut event = next_live_event(&mut socket_a).await;
+    let expense = event.as_object_mut().unwrap().remove("expense").unwrap();
+    assert_eq!(event, json!({ "id": 3, "type": "expense_created" }));
+    assert_eq!(expense["person_name"], json!("person A"));
+    assert_eq!(expense["amount"], json!("12.5000"));
+    assert_eq!(
+        next_live_event(&mut socket_a).await,
+        json!({ "id": 4, "type": "person_joined", "name": "person B" })
+    );
+    let event = next_live_event(&mut socket_a).await;
+    assert_eq!(event["id"], json!(5));
+    assert_eq!(event["expense"]["person_name"], json!("person B"));
+
+    // Connecting with a last_event_id replays everything after it, so this
+    // client sees those events even though they happened before it connected
+    let (mut socket_b, _) = connect_async(format!("{}?last_event_id=2", &live_uri))
+        .await
+        .unwrap();
+    for (id, event_type) in [
+        (3, "expense_created"),
+        (4, "person_joined"),
+        (5, "expense_created"),
+    ] {
+        let event = next_live_event(&mut socket_b).await;
+        assert_eq!(event["id"], json!(id));
+        assert_eq!(event["type"], json!(event_type));
+    }
+
+    // Presence goes out to everyone, and leaving is announced on disconnect
+    socket_a
+        .send(Message::Text(
+            json!({ "type": "presence", "name": "person A", "activity": "adding_expense" })
+                .to_string(),
+        ))
+        .await
+        .unwrap();
+
+    let expt_presence =
+        json!({ "id": 6, "type": "presence", "name": "person A", "activity": "adding_expense" });
+    assert_eq!(next_live_event(&mut socket_a).await, expt_presence);
+    assert_eq!(next_live_event(&mut socket_b).await, expt_presence);
+
+    socket_a.close(None).await.unwrap();
+    assert_eq!(
+        next_live_event(&mut socket_b).await,
+        json!({ "id": 7, "type": "presence_left", "name": "person A" })
+    );
+
+    // Presence is never replayed, so reconnecting from before it only tells
+    // us about the durable events
+    let (mut socket_c, _) = connect_async(format!("{}?last_event_id=1", &live_uri))
+        .await
+        .unwrap();
+    assert_eq!(next_live_event(&mut socket_c).await["id"], json!(2));
+
+    // Event IDs from the future just get a resync
+    let (mut socket_d, _) = connect_async(format!("{}?last_event_id={}", &live_uri, u64::MAX))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_live_event(&mut socket_d).await,
+        json!({ "id": u64::MAX, "type": "resync" })
+    );
+
+    // Once everybody else is gone, the last client can still drop off and pick
+    // up where it left off, even though its leaving was announced to nobody
+    for mut socket in [socket_c, socket_d] {
+        socket.close(None).await.unwrap();
+    }
+    socket_b
+        .send(Message::Text(
+            json!({ "type": "presence", "name": "person B", "activity": null }).to_string(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(next_live_event(&mut socket_b).await["id"], json!(8));
+    socket_b.close(None).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/api/expenses")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "outing_id": &outing_id,
+                        "person_name": "person B",
+                        "amount": 1
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let (mut socket_b, _) = connect_async(format!("{}?last_event_id=8", &live_uri))
+        .await
+        .unwrap();
+    let event = next_live_event(&mut socket_b).await;
+    assert_eq!(event["id"], json!(10));
+    assert_eq!(event["type"], json!("expense_created"));
+
+    // Unknown outings are rejected before upgrading
+    let missing_id = birdie::models::HARSH.encode(&[2]);
+    assert!(
+        connect_async(format!("ws://{}/api/outings/{}/live", addr, missing_id))
+            .await
+            .is_err()
+    );
+
+    cleanup(pool, "live_updates").await;
 }
 
 #[tokio::test]